@@ -49,6 +49,10 @@ impl AppInstance {
     pub async fn get_app_status(&self) -> AppStatus {
         Self::get_mutex_value(&self.app_status).await
     }
+    // EXITING or EXITED, background loops stop on it
+    pub async fn is_exiting(&self) -> bool {
+        matches!(self.get_app_status().await, AppStatus::EXITING(_) | AppStatus::EXITED)
+    }
     pub async fn get_service_api_root(&self) -> String {
         Self::get_mutex_value(&self._service_api_root).await
     }
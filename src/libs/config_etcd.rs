@@ -0,0 +1,129 @@
+// overlays the toml config with keys under [etcd] config_prefix and follows their changes.
+// every key holds the json form of one config field, e.g. <prefix>/redis = [{"host":..,"port":..}]
+
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use log::{error, info, warn};
+use tokio::sync::broadcast;
+use crate::libs::types;
+use crate::libs::config::{get_config, ConfigChange, ExampleConfig};
+use crate::libs::etcd_impl::EtcdInst;
+use crate::libs::etcd_kv::{EtcdKv, EtcdKvEvent};
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::log::log_impl::SysLogger;
+use crate::libs::redis_async_pool::get_redis_async_pool;
+use crate::libs::redis_pool::get_redis_pool;
+
+// changes a slow subscriber may lag behind before it starts losing them
+const CONFIG_CHANGES_BUFFER : usize = 64;
+
+lazy_static!(
+  static ref CONFIG_CHANGES : broadcast::Sender<ConfigChange> = broadcast::channel(CONFIG_CHANGES_BUFFER).0;
+);
+
+// changes applied to get_config() from now on, subscribing before start_config_overlay also gets the initial overlay
+pub fn subscribe_config_changes() -> broadcast::Receiver<ConfigChange> {
+    CONFIG_CHANGES.subscribe()
+}
+
+// pools already built move to the new nodes, one never built picks them up on first use.
+// dedicated connections (RedisOp::connect) switch when they reconnect
+async fn rebuild_redis_pools() {
+    let uri = get_config().lock().await.get_redis_config();
+    if !get_redis_pool().nodes().await.is_empty() {
+        if let Err(e) = get_redis_pool().rebuild(uri.clone()).await {
+            error!("config overlay redis pool rebuild failed, err {}\n", e);
+        }
+    }
+    if !get_redis_async_pool().nodes().await.is_empty() {
+        if let Err(e) = get_redis_async_pool().rebuild(uri).await {
+            error!("config overlay redis async pool rebuild failed, err {}\n", e);
+        }
+    }
+}
+
+async fn apply(base : &ExampleConfig, key : &str, value : Option<&serde_json::Value>) {
+    let change = match get_config().lock().await.overlay(base, key, value) {
+        Ok(Some(v)) => { v }
+        Ok(None) => { return; }
+        Err(e) => {
+            error!("config overlay#{} rejected, keep current value, err {}\n", key, e);
+            return;
+        }
+    };
+    match &change {
+        ConfigChange::LogLevel(level) => {
+            SysLogger::set_log_level(level.clone());
+        }
+        ConfigChange::RedisNodes(_) => {
+            rebuild_redis_pools().await;
+        }
+        _ => {}
+    }
+    // values may hold credentials, only the key is logged
+    info!("config overlay#{} applied\n", key);
+    // no subscriber is fine
+    let _ = CONFIG_CHANGES.send(change);
+}
+
+// applies value unless it is the one already overlaid, so a resync doesn't reload unchanged keys
+async fn apply_changed(base : &ExampleConfig, applied : &mut HashMap<String, serde_json::Value>, key : &str, value : &serde_json::Value) {
+    if applied.get(key) == Some(value) {
+        return;
+    }
+    apply(base, key, Some(value)).await;
+    applied.insert(key.to_string(), value.clone());
+}
+
+// values overlaid at the moment, a resync falls back to base for the keys that vanished
+async fn future_config_watch_handle(kv : EtcdKv<serde_json::Value>, base : ExampleConfig, revision : i64, mut applied : HashMap<String, serde_json::Value>) {
+    let mut watch = kv.watch("", revision);
+    while let Some(ev) = watch.next().await {
+        if get_app_instance().is_exiting().await {
+            break;
+        }
+        match ev {
+            EtcdKvEvent::Put { entry, .. } => {
+                apply_changed(&base, &mut applied, &entry.key, &entry.value).await;
+            }
+            EtcdKvEvent::Delete { key, .. } => {
+                apply(&base, &key, None).await;
+                applied.remove(&key);
+            }
+            EtcdKvEvent::Resync(range) => {
+                let current : HashSet<&String> = range.entries.iter().map(|x| &x.key).collect();
+                let vanished : Vec<String> = applied.keys().filter(|x| !current.contains(x)).cloned().collect();
+                for key in vanished.iter() {
+                    apply(&base, key, None).await;
+                    applied.remove(key);
+                }
+                for e in range.entries.iter() {
+                    apply_changed(&base, &mut applied, &e.key, &e.value).await;
+                }
+            }
+        }
+    }
+    warn!("config overlay watch stopped\n");
+}
+
+// call after ExampleConfig::parse. false when no config_prefix is configured
+pub async fn start_config_overlay() -> types::Result<bool> {
+    let (endpoints, etcd, base) = {
+        let c = get_config().lock().await;
+        (c.get_etcd_endpoints(), c.get_etcd_config(), c.clone())
+    };
+    let prefix = match &etcd.config_prefix {
+        Some(v) => { format!("{}/", v.trim_end_matches('/')) }
+        None => { return Ok(false); }
+    };
+    let client = EtcdInst::create_etcd_client_with_config(endpoints, &etcd).await?;
+    let kv = EtcdKv::<serde_json::Value>::new(client, &prefix);
+    let range = kv.list("").await?;
+    info!("config overlay {} : {} keys at revision {}\n", prefix, range.entries.len(), range.revision);
+    for e in range.entries.iter() {
+        apply(&base, &e.key, Some(&e.value)).await;
+    }
+    let applied = range.entries.into_iter().map(|x| (x.key, x.value)).collect();
+    tokio::spawn(future_config_watch_handle(kv, base, range.revision + 1, applied));
+    Ok(true)
+}
@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::fmt;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+// whether repeating the same call may succeed, decided from the source error.
+// redirects, failover and io problems are transient, bad data or config is not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryClass {
+    Retryable,
+    Fatal,
+}
+
+// crate wide error, one variant per subsystem.
+// context tells what was being done, source keeps the underlying error
+// so callers can still downcast it (see redis_error / is_timeout)
+#[derive(Debug)]
+pub enum AppCommonError {
+    Redis { context : String, source : Option<BoxError> },
+    NotFound { key : String },
+    Etcd { context : String, source : Option<BoxError> },
+    Http { context : String, source : Option<BoxError> },
+    Json { context : String, source : Option<BoxError> },
+    // binary formats and compression of redis values
+    Codec { context : String, source : Option<BoxError> },
+    Config { context : String, source : Option<BoxError> },
+    Log { context : String, source : Option<BoxError> },
+    Register { context : String, source : Option<BoxError> },
+}
+
+impl AppCommonError {
+    pub fn redis<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Redis { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn redis_msg(context : impl Into<String>) -> Self {
+        Self::Redis { context : context.into(), source : None }
+    }
+    pub fn not_found(key : impl Into<String>) -> Self {
+        Self::NotFound { key : key.into() }
+    }
+    pub fn etcd<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Etcd { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn etcd_msg(context : impl Into<String>) -> Self {
+        Self::Etcd { context : context.into(), source : None }
+    }
+    pub fn http<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Http { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn http_msg(context : impl Into<String>) -> Self {
+        Self::Http { context : context.into(), source : None }
+    }
+    pub fn json<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Json { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn json_msg(context : impl Into<String>) -> Self {
+        Self::Json { context : context.into(), source : None }
+    }
+    pub fn codec<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Codec { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn codec_msg(context : impl Into<String>) -> Self {
+        Self::Codec { context : context.into(), source : None }
+    }
+    pub fn config<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Config { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn config_msg(context : impl Into<String>) -> Self {
+        Self::Config { context : context.into(), source : None }
+    }
+    pub fn log<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Log { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn register<E>(context : impl Into<String>, e : E) -> Self
+        where E : Into<BoxError>
+    {
+        Self::Register { context : context.into(), source : Some(e.into()) }
+    }
+    pub fn register_msg(context : impl Into<String>) -> Self {
+        Self::Register { context : context.into(), source : None }
+    }
+
+    pub fn subsystem(&self) -> &'static str {
+        match self {
+            Self::Redis { .. } => "redis",
+            Self::NotFound { .. } => "redis",
+            Self::Etcd { .. } => "etcd",
+            Self::Http { .. } => "http",
+            Self::Json { .. } => "json",
+            Self::Codec { .. } => "codec",
+            Self::Config { .. } => "config",
+            Self::Log { .. } => "log",
+            Self::Register { .. } => "register",
+        }
+    }
+
+    fn source_ref(&self) -> Option<&BoxError> {
+        match self {
+            Self::Redis { source, .. } |
+            Self::Etcd { source, .. } |
+            Self::Http { source, .. } |
+            Self::Json { source, .. } |
+            Self::Codec { source, .. } |
+            Self::Config { source, .. } |
+            Self::Log { source, .. } |
+            Self::Register { source, .. } => source.as_ref(),
+            Self::NotFound { .. } => None,
+        }
+    }
+
+    pub fn redis_error(&self) -> Option<&redis::RedisError> {
+        self.source_ref().and_then(|x| x.downcast_ref::<redis::RedisError>())
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound { .. })
+    }
+
+    pub fn retry_class(&self) -> RetryClass {
+        if let Self::Codec { .. } = self {
+            // corrupt data decodes no better the second time, whatever io error zstd reports
+            return RetryClass::Fatal;
+        }
+        let source = match self.source_ref() {
+            Some(v) => { v }
+            None => {
+                return RetryClass::Fatal;
+            }
+        };
+        let retryable = if let Some(e) = source.downcast_ref::<redis::RedisError>() {
+            Self::redis_retryable(e)
+        } else if let Some(e) = source.downcast_ref::<r2d2_redis_cluster::redis_cluster_rs::redis::RedisError>() {
+            e.is_io_error() || e.is_timeout() || e.is_connection_dropped() || e.is_connection_refusal()
+        } else if source.downcast_ref::<r2d2_redis_cluster::r2d2::Error>().is_some() {
+            // pool checkout timed out
+            true
+        } else if let Some(e) = source.downcast_ref::<etcd_rs::Error>() {
+            match e {
+                etcd_rs::Error::Response(s) => {
+                    matches!(s.code(), tonic::Code::Unavailable | tonic::Code::DeadlineExceeded) || Self::etcd_timed_out(s)
+                }
+                _ => {
+                    matches!(e, etcd_rs::Error::IOError(_) |
+                        etcd_rs::Error::Transport(_) |
+                        etcd_rs::Error::ChannelClosed |
+                        etcd_rs::Error::KeepAliveLease)
+                }
+            }
+        } else if let Some(e) = source.downcast_ref::<reqwest::Error>() {
+            e.is_timeout() || e.is_connect()
+        } else if let Some(e) = source.downcast_ref::<std::io::Error>() {
+            matches!(e.kind(),
+                std::io::ErrorKind::TimedOut |
+                std::io::ErrorKind::ConnectionReset |
+                std::io::ErrorKind::ConnectionRefused |
+                std::io::ErrorKind::ConnectionAborted |
+                std::io::ErrorKind::BrokenPipe |
+                std::io::ErrorKind::Interrupted)
+        } else {
+            false
+        };
+        if retryable {
+            RetryClass::Retryable
+        } else {
+            RetryClass::Fatal
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retry_class() == RetryClass::Retryable
+    }
+
+    fn redis_retryable(e : &redis::RedisError) -> bool {
+        if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() || e.is_connection_refusal() {
+            return true;
+        }
+        matches!(e.kind(),
+            redis::ErrorKind::Moved |
+            redis::ErrorKind::Ask |
+            redis::ErrorKind::TryAgain |
+            redis::ErrorKind::ClusterDown |
+            redis::ErrorKind::MasterDown |
+            redis::ErrorKind::BusyLoadingError |
+            redis::ErrorKind::ReadOnly)
+    }
+
+    // the channel timeout of an etcd client ends the call as Cancelled "Timeout expired"
+    fn etcd_timed_out(s : &tonic::Status) -> bool {
+        s.code() == tonic::Code::Cancelled && s.message() == "Timeout expired"
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        if let Some(e) = self.redis_error() {
+            return e.is_timeout();
+        }
+        match self.source_ref() {
+            Some(x) => {
+                if let Some(e) = x.downcast_ref::<reqwest::Error>() {
+                    return e.is_timeout();
+                }
+                if let Some(e) = x.downcast_ref::<std::io::Error>() {
+                    return e.kind() == std::io::ErrorKind::TimedOut;
+                }
+                if let Some(etcd_rs::Error::Response(s)) = x.downcast_ref::<etcd_rs::Error>() {
+                    return s.code() == tonic::Code::DeadlineExceeded || Self::etcd_timed_out(s);
+                }
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for AppCommonError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        let context = match self {
+            Self::NotFound { key } => {
+                return write!(f, "{} error: key {} not found", self.subsystem(), key);
+            }
+            Self::Redis { context, .. } |
+            Self::Etcd { context, .. } |
+            Self::Http { context, .. } |
+            Self::Json { context, .. } |
+            Self::Codec { context, .. } |
+            Self::Config { context, .. } |
+            Self::Log { context, .. } |
+            Self::Register { context, .. } => context,
+        };
+        match self.source_ref() {
+            Some(e) => write!(f, "{} error: {}: {}", self.subsystem(), context, e),
+            None => write!(f, "{} error: {}", self.subsystem(), context),
+        }
+    }
+}
+
+impl Error for AppCommonError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.source_ref() {
+            Some(e) => Some(e.as_ref()),
+            None => None,
+        }
+    }
+}
+
+impl From<redis::RedisError> for AppCommonError {
+    fn from(e : redis::RedisError) -> Self {
+        Self::redis("redis command", e)
+    }
+}
+
+impl From<etcd_rs::Error> for AppCommonError {
+    fn from(e : etcd_rs::Error) -> Self {
+        Self::etcd("etcd request", e)
+    }
+}
+
+impl From<reqwest::Error> for AppCommonError {
+    fn from(e : reqwest::Error) -> Self {
+        Self::http("http request", e)
+    }
+}
+
+impl From<serde_json::Error> for AppCommonError {
+    fn from(e : serde_json::Error) -> Self {
+        Self::json("json", e)
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::Arc;
+use std::time::Duration;
+use etcd_rs::{Client, KeyRange, KeyValue, KeyValueOp, LeaseId, LeaseKeepAlive, PutRequest, RangeRequest, WatchCreateRequest,
+              WatchInbound, WatchOp};
+use log::{error, info, warn};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::etcd_impl::EtcdInst;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::etcd_lock::{grant_lease, refresh_lease, revoke_lease, EtcdLock};
+
+// how often the keeper looks for SIGTERM, well below the 1s the signal hook waits before exit
+const ELECTION_EXIT_CHECK : Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EtcdLeader {
+  pub key : String,
+  // app uuid of the leading instance
+  pub owner : String,
+  // create revision of the leader key, grows with every term, usable as fencing token
+  pub revision : i64,
+}
+
+impl From<&KeyValue> for EtcdLeader {
+  fn from(kv : &KeyValue) -> Self {
+    Self{
+      key: kv.key_str().to_string(),
+      owner: kv.value_str().to_string(),
+      revision: kv.create_revision,
+    }
+  }
+}
+
+struct EtcdCandidate {
+  key : String,
+  lease_id : LeaseId,
+  revision : i64,
+  keeper : JoinHandle<()>,
+}
+
+// leader election under prefix, same recipe as etcd's concurrency package: every candidate puts
+// <prefix>/<lease id> bound to its lease, the key with the lowest create revision leads and each
+// candidate waits on the deletion of the one created right before it
+#[derive(Clone)]
+pub struct EtcdElection {
+  client : Client,
+  prefix : String,
+  ttl : Duration,
+  is_leader : Arc<watch::Sender<bool>>,
+  candidate : Arc<Mutex<Option<EtcdCandidate>>>,
+}
+
+impl EtcdElection {
+  pub fn new(client : Client, prefix : &str, ttl : Duration) -> Self {
+    let (tx, _) = watch::channel(false);
+    Self{
+      client,
+      prefix: prefix.trim_end_matches('/').to_string(),
+      ttl,
+      is_leader: Arc::new(tx),
+      candidate: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  pub fn is_leader(&self) -> bool {
+    *self.is_leader.borrow()
+  }
+
+  // "am I leader" changes of this instance
+  pub fn subscribe(&self) -> watch::Receiver<bool> {
+    self.is_leader.subscribe()
+  }
+
+  // blocks until this instance leads, returns the leader revision.
+  // the lease is kept alive in the background, losing it or the app exiting ends the term
+  pub async fn campaign(&self) -> types::Result<i64> {
+    let owner = get_app_instance().get_application_uuid().await;
+    let (key, revision) = {
+      let mut candidate = self.candidate.lock().await;
+      if let Some(c) = candidate.as_ref() {
+        return Err(AppCommonError::etcd_msg(format!("election#{} already campaigning as {}", self.prefix, c.key)));
+      }
+      let lease_id = grant_lease(&self.client, self.ttl).await?;
+      let key = format!("{}/{:x}", self.prefix, lease_id);
+      let put = self.client.put(PutRequest::new(key.as_str(), owner.as_str()).lease(lease_id));
+      let revision = match EtcdInst::request(format!("election#{} campaign put", key), put).await {
+        Ok(v) => { v.header.revision() }
+        Err(e) => {
+          revoke_lease(&self.client, &key, lease_id).await;
+          return Err(e);
+        }
+      };
+      let keeper = tokio::spawn(self.clone().keep_alive(key.clone(), lease_id));
+      *candidate = Some(EtcdCandidate{
+        key: key.clone(),
+        lease_id,
+        revision,
+        keeper,
+      });
+      (key, revision)
+    };
+    info!("election#{} campaign as {}, revision {}\n", self.prefix, key, revision);
+    if let Err(e) = self.wait_turn(&key, revision).await {
+      if let Err(x) = self.resign().await {
+        error!("election#{} resign after failed campaign, err {}\n", self.prefix, x);
+      }
+      return Err(e);
+    }
+    self.is_leader.send_replace(true);
+    info!("election#{} elected, {} revision {}\n", self.prefix, key, revision);
+    Ok(revision)
+  }
+
+  async fn wait_turn(&self, key : &str, revision : i64) -> types::Result<()> {
+    let interval = (self.ttl / 3).max(Duration::from_millis(100));
+    loop {
+      match self.candidate.lock().await.as_ref() {
+        Some(c) if c.key == key => {}
+        _ => {
+          return Err(AppCommonError::etcd_msg(format!("election#{} resigned while campaigning", key)));
+        }
+      }
+      let (kvs, header_revision) = self.candidates().await?;
+      if !kvs.iter().any(|x| x.key_str() == key) {
+        return Err(AppCommonError::etcd_msg(format!("election#{} candidate key expired", key)));
+      }
+      let previous = kvs.iter()
+          .filter(|x| x.create_revision < revision)
+          .max_by_key(|x| x.create_revision);
+      let previous = match previous {
+        Some(v) => { v.key_str().to_string() }
+        None => { return Ok(()); }
+      };
+      // bounded so a resign or an exit during the wait is noticed
+      if let Ok(r) = tokio::time::timeout(interval, EtcdLock::wait_deleted(&self.client, &previous, header_revision)).await {
+        r?;
+      }
+    }
+  }
+
+  async fn candidates(&self) -> types::Result<(Vec<KeyValue>, i64)> {
+    let prefix = format!("{}/", self.prefix);
+    let v = EtcdInst::request(format!("election#{} range", self.prefix), self.client.get(RangeRequest::new(KeyRange::prefix(prefix)))).await?;
+    Ok((v.kvs, v.header.revision()))
+  }
+
+  // ends the term or the running campaign, no-op when not campaigning
+  pub async fn resign(&self) -> types::Result<()> {
+    let candidate = match self.candidate.lock().await.take() {
+      Some(v) => { v }
+      None => { return Ok(()); }
+    };
+    candidate.keeper.abort();
+    self.is_leader.send_replace(false);
+    info!("election#{} resign {}, revision {}\n", self.prefix, candidate.key, candidate.revision);
+    Self::delete_candidate(&self.client, &candidate.key, candidate.lease_id).await
+  }
+
+  async fn delete_candidate(client : &Client, key : &str, lease_id : LeaseId) -> types::Result<()> {
+    let r = EtcdInst::request(format!("election#{} resign delete", key), client.delete(KeyRange::key(key))).await;
+    revoke_lease(client, key, lease_id).await;
+    r?;
+    Ok(())
+  }
+
+  // current leader of the prefix, whoever it is
+  pub async fn leader(&self) -> types::Result<Option<EtcdLeader>> {
+    let (kvs, _) = self.candidates().await?;
+    Ok(kvs.iter().min_by_key(|x| x.create_revision).map(EtcdLeader::from))
+  }
+
+  // follows the leader of the prefix until the app exits or every receiver is dropped
+  pub fn observe(&self) -> watch::Receiver<Option<EtcdLeader>> {
+    let (tx, rx) = watch::channel(None);
+    let election = self.clone();
+    tokio::spawn(async move {
+      loop {
+        if tx.is_closed() || get_app_instance().is_exiting().await {
+          info!("election#{} stop observing\n", election.prefix);
+          return;
+        }
+        let revision = match election.candidates().await {
+          Ok((kvs, revision)) => {
+            let leader = kvs.iter().min_by_key(|x| x.create_revision).map(EtcdLeader::from);
+            tx.send_if_modified(|x| {
+              if *x == leader {
+                return false;
+              }
+              *x = leader;
+              true
+            });
+            revision
+          }
+          Err(e) => {
+            error!("election#{} observe failed, err {}\n", election.prefix, e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+          }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(1), election.wait_changed(revision)).await;
+      }
+    });
+    rx
+  }
+
+  async fn wait_changed(&self, revision : i64) {
+    let req = WatchCreateRequest::create(KeyRange::prefix(format!("{}/", self.prefix))).start_revision(revision + 1);
+    let (mut stream, canceler) = match self.client.watch(req).await {
+      Ok(v) => { v }
+      Err(e) => {
+        warn!("election#{} watch failed, err {}\n", self.prefix, e);
+        return;
+      }
+    };
+    loop {
+      match stream.inbound().await {
+        WatchInbound::Ready(resp) => {
+          if !resp.events.is_empty() {
+            break;
+          }
+        }
+        WatchInbound::Interrupted(e) => {
+          warn!("election#{} watch interrupted, err {}\n", self.prefix, e);
+          break;
+        }
+        WatchInbound::Closed => { break; }
+      }
+    }
+    let _ = canceler.cancel().await;
+  }
+
+  // the term ends and the candidate is cleared, a new campaign() may start right after
+  async fn end_term(&self, key : &str) {
+    self.is_leader.send_replace(false);
+    let mut candidate = self.candidate.lock().await;
+    if candidate.as_ref().map(|x| x.key == key).unwrap_or(false) {
+      *candidate = None;
+    }
+  }
+
+  async fn keep_alive(self, key : String, lease_id : LeaseId) {
+    let mut refresh = tokio::time::interval((self.ttl / 3).max(Duration::from_millis(100)));
+    let mut exit_check = tokio::time::interval(ELECTION_EXIT_CHECK);
+    let mut refreshed = tokio::time::Instant::now();
+    let mut lease_alive : Option<LeaseKeepAlive> = None;
+    loop {
+      tokio::select! {
+        _ = exit_check.tick() => {
+          if get_app_instance().is_exiting().await {
+            // hand leadership over right away instead of after the lease ttl
+            info!("election#{} resign on exit\n", key);
+            self.end_term(&key).await;
+            if let Err(e) = Self::delete_candidate(&self.client, &key, lease_id).await {
+              error!("election#{} resign on exit failed, err {}\n", key, e);
+            }
+            return;
+          }
+        }
+        _ = refresh.tick() => {
+          if !refresh_lease(&self.client, &key, lease_id, self.ttl, &mut refreshed, &mut lease_alive).await {
+            warn!("election#{} lost, lease {} expired\n", key, lease_id);
+            self.end_term(&key).await;
+            return;
+          }
+        }
+      }
+    }
+  }
+}
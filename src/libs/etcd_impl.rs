@@ -1,47 +1,137 @@
+use std::future::Future;
 use std::time::Duration;
 use etcd_rs;
-use etcd_rs::{Client, ClientConfig, Endpoint, LeaseId, LeaseGrantRequest, LeaseOp, PutRequest, KeyValueOp, LeaseKeepAlive, LeaseRevokeRequest, TxnRequest, TxnCmp, KeyRange, RangeRequest, TxnOp};
+use etcd_rs::{AuthOp, Client, LeaseId, LeaseGrantRequest, LeaseOp, PutRequest, KeyValueOp, LeaseKeepAlive, LeaseRevokeRequest, TxnRequest, TxnCmp, KeyRange, RangeRequest, TxnOp};
 use log::{error, info};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 use crate::libs::app::app_inst::{AppStatus, get_app_instance};
 use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::config::{get_config, EtcdConfig};
+
+// same as etcd-rs ClientConfig
+const ETCD_HTTP2_KEEP_ALIVE : Duration = Duration::from_secs(5);
 
 #[derive(Default)]
 pub struct EtcdInst {}
 
 impl EtcdInst {
+  // default lease_ttl_secs of the [etcd] config section
   pub const TTL_LEASE : i64 = 10;
+
+  // tls, auth and connect timeout from the [etcd] config section
   pub async fn create_etcd_client(endpoints : Vec<String>) -> types::Result<Client> {
-    let etcd_endpoints = endpoints
-        .iter()
-        .map(|x| Endpoint::new(x))
-        .collect::<Vec<Endpoint>>();
-    let client_res = Client::connect(ClientConfig::new(etcd_endpoints)).await;
-    let cli = match client_res {
-      Ok(v) => { v }
-      Err(e) => { return Err(e.to_string())?; }
-    };
+    let config = get_config().lock().await.get_etcd_config();
+    Self::create_etcd_client_with_config(endpoints, &config).await
+  }
+
+  // the channel is built here instead of by etcd-rs, whose Endpoint only does mutual tls
+  pub async fn create_etcd_client_with_config(endpoints : Vec<String>, config : &EtcdConfig) -> types::Result<Client> {
+    let mut channels = Vec::with_capacity(endpoints.len());
+    for x in endpoints.iter() {
+      let mut channel = match Channel::from_shared(x.clone()) {
+        Ok(v) => { v }
+        Err(e) => {
+          return Err(AppCommonError::config(format!("etcd endpoint {}", x), e));
+        }
+      };
+      channel = channel.connect_timeout(config.connect_timeout()).http2_keep_alive_interval(ETCD_HTTP2_KEEP_ALIVE);
+      // bounds a call until the response headers arrive, so a watch or keep alive stream
+      // that already got its first answer runs on unlimited
+      if let Some(t) = config.request_timeout() {
+        channel = channel.timeout(t);
+      }
+      if x.starts_with("https://") {
+        channel = match channel.tls_config(Self::tls_config(x, config).await?) {
+          Ok(v) => { v }
+          Err(e) => {
+            return Err(AppCommonError::etcd(format!("etcd {} : tls", x), e));
+          }
+        };
+      }
+      channels.push(channel);
+    }
+    let channel = Channel::balance_list(channels.into_iter());
+    let mut cli = Client::with_channel(channel.clone(), None);
+    if let (Some(user), Some(password)) = (&config.username, &config.password) {
+      let token = match cli.authenticate((user.clone(), password.clone())).await {
+        Ok(v) => { v.token }
+        Err(e) => { return Err(AppCommonError::etcd("connect", e)); }
+      };
+      cli = Client::with_channel(channel, Some(token));
+    }
     Ok(cli)
   }
 
-  async fn create_lease(client: &Client, ttl: i64) -> Result<LeaseId, Box<dyn std::error::Error>> {
-    let lease_request = LeaseGrantRequest::new(Duration::from_secs(ttl as u64));
-    let lease_response = match client.grant_lease(lease_request).await {
-      Ok(v) => { v }
-      Err(e) => {
-        return Err(e.to_string())?;
+  // the server is verified against ca_cert, client_cert and client_key add a client certificate
+  async fn tls_config(url : &str, config : &EtcdConfig) -> types::Result<ClientTlsConfig> {
+    let ca_cert = match &config.ca_cert {
+      Some(v) => { v }
+      None => {
+        return Err(AppCommonError::config_msg(format!("etcd {} : https needs ca_cert", url)));
       }
     };
-    Ok(lease_response.id)
+    let domain = match &config.tls_domain {
+      Some(v) => { v.clone() }
+      None => { Self::endpoint_host(url) }
+    };
+    let mut tls = ClientTlsConfig::new()
+        .domain_name(domain)
+        .ca_certificate(Certificate::from_pem(Self::read_pem(url, ca_cert).await?));
+    match (&config.client_cert, &config.client_key) {
+      (Some(cert), Some(key)) => {
+        tls = tls.identity(Identity::from_pem(Self::read_pem(url, cert).await?, Self::read_pem(url, key).await?));
+      }
+      (None, None) => {}
+      _ => {
+        return Err(AppCommonError::config_msg(format!("etcd {} : client_cert and client_key go together", url)));
+      }
+    }
+    Ok(tls)
   }
 
-  pub async fn create_lease_id(client: &Client,) -> types::Result<LeaseId> {
-    let lease_id = match Self::create_lease(&client, Self::TTL_LEASE).await {
-      Ok(v) => { v }
+  async fn read_pem(url : &str, path : &str) -> types::Result<Vec<u8>> {
+    match tokio::fs::read(path).await {
+      Ok(v) => { Ok(v) }
       Err(e) => {
-        return Err(e.to_string())?;
+        Err(AppCommonError::config(format!("etcd {} : load tls certificate {}", url, path), e))
       }
-    };
-    Ok(lease_id)
+    }
+  }
+
+  // host of scheme://host:port
+  fn endpoint_host(url : &str) -> String {
+    let rest = url.split("://").nth(1).unwrap_or(url);
+    let authority = rest.split('/').next().unwrap_or(rest);
+    match authority.rsplit_once(':') {
+      Some((host, _)) => { host.trim_matches(|c| c == '[' || c == ']').to_string() }
+      None => { authority.to_string() }
+    }
+  }
+
+  // runs an etcd call, the request timeout is part of the client channel
+  pub async fn request<T, F>(context : impl Into<String>, fut : F) -> types::Result<T>
+    where F : Future<Output = etcd_rs::Result<T>>
+  {
+    match fut.await {
+      Ok(v) => { Ok(v) }
+      Err(e) => { Err(AppCommonError::etcd(context, e)) }
+    }
+  }
+
+  // lease_ttl_secs of the [etcd] config section
+  pub async fn lease_ttl() -> Duration {
+    get_config().lock().await.get_etcd_config().lease_ttl()
+  }
+
+  async fn create_lease(client: &Client, ttl: Duration) -> types::Result<LeaseId> {
+    let lease_request = LeaseGrantRequest::new(ttl);
+    let lease_response = Self::request("grant lease", client.grant_lease(lease_request)).await?;
+    Ok(lease_response.id)
+  }
+
+  pub async fn create_lease_id(client: &Client,) -> types::Result<LeaseId> {
+    Self::create_lease(client, Self::lease_ttl().await).await
   }
 
   pub async fn acquire_lock(
@@ -58,16 +148,10 @@ impl EtcdInst {
         .when_create_revision(key_range.clone(), TxnCmp::Equal, 0)
         .and_then(TxnOp::Put(put_request))
         .or_else(TxnOp::Range(range_request));
-    match client.txn(txn_request).await {
-      Ok(v) => {
-        info!("acquire_lock# pull response : {:?}\n", v);
-        if !v.succeeded {
-          return Err("acquire_lock# txn response with unsuccessful".to_string())?;
-        }
-      }
-      Err(e) => {
-        return Err(e.to_string())?;
-      }
+    let v = Self::request(format!("acquire_lock#{} txn", key), client.txn(txn_request)).await?;
+    info!("acquire_lock# pull response : {:?}\n", v);
+    if !v.succeeded {
+      return Err(AppCommonError::etcd_msg(format!("acquire_lock#{} txn response with unsuccessful", key)));
     }
     Ok(())
   }
@@ -77,16 +161,10 @@ impl EtcdInst {
     key: &str,
     lease_id: LeaseId,
   ) -> types::Result<()> {
-    client.delete(key).await?;
+    Self::request(format!("release_lock#{} delete", key), client.delete(key)).await?;
     let revoke_req = LeaseRevokeRequest::new(lease_id);
-    match client.revoke(revoke_req).await {
-      Ok(v) => {
-        info!("release_lock# pull response : {:?}\n", v);
-      }
-      Err(e) => {
-        return Err(e.to_string())?
-      }
-    }
+    let v = Self::request(format!("release_lock#{} revoke lease", key), client.revoke(revoke_req)).await?;
+    info!("release_lock# pull response : {:?}\n", v);
     Ok(())
   }
 
@@ -116,6 +194,7 @@ impl EtcdInst {
     }
   }
 
+  // fixed cadence for a caller owned lease, etcd_lease::EtcdLeaseKeeper follows the ttl and replaces lost leases
   pub async fn keep_lease_alive(client: &Client, lease_id: LeaseId, secs : u64) {
     info!("start keep lease alive, lease_id {}\n", lease_id);
     let mut lease_alive = Self::create_lease_alive_client(client, lease_id).await;
@@ -0,0 +1,360 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::time::Duration;
+use etcd_rs::{Client, DeleteRequest, Event, EventType, KeyRange, KeyValue, KeyValueOp, LeaseId, PutRequest, RangeRequest,
+              TxnCmp, TxnOp, TxnRequest, WatchCreateRequest, WatchInbound, WatchOp};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::etcd_impl::EtcdInst;
+
+const ETCD_KV_WATCH_BUFFER : usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct EtcdEntry<T> {
+  // relative to the store prefix
+  pub key : String,
+  pub value : T,
+  pub create_revision : i64,
+  pub mod_revision : i64,
+  pub version : i64,
+  pub lease : LeaseId,
+}
+
+// entries of a range and the store revision they were read at, watch from revision + 1 to follow up
+#[derive(Debug, Clone)]
+pub struct EtcdRange<T> {
+  pub entries : Vec<EtcdEntry<T>>,
+  pub revision : i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum EtcdKvEvent<T> {
+  Put { entry : EtcdEntry<T>, prev : Option<T> },
+  Delete { key : String, revision : i64, prev : Option<T> },
+  // the watched revision was compacted away, so changes in between are unknown.
+  // holds everything under the watched prefix now, replace local state with it
+  Resync(EtcdRange<T>),
+}
+
+impl<T> EtcdKvEvent<T> {
+  // empty for Resync
+  pub fn key(&self) -> &str {
+    match self {
+      Self::Put { entry, .. } => { &entry.key }
+      Self::Delete { key, .. } => { key }
+      Self::Resync(_) => { "" }
+    }
+  }
+
+  pub fn revision(&self) -> i64 {
+    match self {
+      Self::Put { entry, .. } => { entry.mod_revision }
+      Self::Delete { revision, .. } => { *revision }
+      Self::Resync(range) => { range.revision }
+    }
+  }
+}
+
+// typed json values under prefix, keys passed in and handed out are relative to it
+pub struct EtcdKv<T> {
+  client : Client,
+  prefix : String,
+  // fn() keeps EtcdKv Send + Sync whatever T is
+  _marker : PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for EtcdKv<T> {
+  fn clone(&self) -> Self {
+    Self{
+      client: self.client.clone(),
+      prefix: self.prefix.clone(),
+      _marker: PhantomData,
+    }
+  }
+}
+
+impl<T> EtcdKv<T>
+    where T : Debug + Serialize + DeserializeOwned + Send + 'static
+{
+  pub fn new(client : Client, prefix : &str) -> Self {
+    Self{
+      client,
+      prefix: prefix.to_string(),
+      _marker: PhantomData,
+    }
+  }
+
+  pub fn client(&self) -> &Client {
+    &self.client
+  }
+
+  fn full_key(&self, key : &str) -> String {
+    format!("{}{}", self.prefix, key)
+  }
+
+  fn encode(key : &str, data : &T) -> types::Result<Vec<u8>> {
+    match serde_json::to_vec(data) {
+      Ok(v) => { Ok(v) }
+      Err(e) => {
+        Err(AppCommonError::json(format!("etcd kv encode#{}", key), e))
+      }
+    }
+  }
+
+  fn decode(key : &str, raw : &[u8]) -> types::Result<T> {
+    match serde_json::from_slice(raw) {
+      Ok(v) => { Ok(v) }
+      Err(e) => {
+        Err(AppCommonError::json(format!("etcd kv decode#{}", key), e))
+      }
+    }
+  }
+
+  fn entry(prefix : &str, kv : &KeyValue) -> types::Result<EtcdEntry<T>> {
+    let key = kv.key_str();
+    Ok(EtcdEntry{
+      key: key.strip_prefix(prefix).unwrap_or(key).to_string(),
+      value: Self::decode(key, &kv.value)?,
+      create_revision: kv.create_revision,
+      mod_revision: kv.mod_revision,
+      version: kv.version,
+      lease: kv.lease,
+    })
+  }
+
+  pub async fn get(&self, key : &str) -> types::Result<Option<EtcdEntry<T>>> {
+    let full = self.full_key(key);
+    let v = EtcdInst::request(format!("etcd kv get#{}", full), self.client.get(RangeRequest::new(KeyRange::key(full.as_str())))).await?;
+    match v.kvs.first() {
+      Some(kv) => { Ok(Some(Self::entry(&self.prefix, kv)?)) }
+      None => { Ok(None) }
+    }
+  }
+
+  // every entry whose key starts with prefix + sub_prefix, "" lists the whole store
+  pub async fn list(&self, sub_prefix : &str) -> types::Result<EtcdRange<T>> {
+    self.list_full(&self.full_key(sub_prefix)).await
+  }
+
+  async fn list_full(&self, full : &str) -> types::Result<EtcdRange<T>> {
+    let resp = EtcdInst::request(format!("etcd kv list#{}", full), self.client.get(RangeRequest::new(KeyRange::prefix(full)))).await?;
+    let mut entries = Vec::with_capacity(resp.kvs.len());
+    for kv in resp.kvs.iter() {
+      entries.push(Self::entry(&self.prefix, kv)?);
+    }
+    Ok(EtcdRange{
+      entries,
+      revision: resp.header.revision(),
+    })
+  }
+
+  // returns the revision of the write
+  pub async fn put(&self, key : &str, data : &T) -> types::Result<i64> {
+    self.put_request(key, data, None).await
+  }
+
+  // the key is deleted together with the lease
+  pub async fn put_with_lease(&self, key : &str, data : &T, lease_id : LeaseId) -> types::Result<i64> {
+    self.put_request(key, data, Some(lease_id)).await
+  }
+
+  async fn put_request(&self, key : &str, data : &T, lease_id : Option<LeaseId>) -> types::Result<i64> {
+    let full = self.full_key(key);
+    debug!("etcd kv put#{} : {:?}\n", full, data);
+    let mut req = PutRequest::new(full.as_str(), Self::encode(&full, data)?);
+    if let Some(id) = lease_id {
+      req = req.lease(id);
+    }
+    let v = EtcdInst::request(format!("etcd kv put#{}", full), self.client.put(req)).await?;
+    Ok(v.header.revision())
+  }
+
+  // true when the key existed
+  pub async fn delete(&self, key : &str) -> types::Result<bool> {
+    let full = self.full_key(key);
+    let v = EtcdInst::request(format!("etcd kv delete#{}", full), self.client.delete(DeleteRequest::new(KeyRange::key(full.as_str())))).await?;
+    Ok(v.deleted > 0)
+  }
+
+  pub async fn delete_prefix(&self, sub_prefix : &str) -> types::Result<u64> {
+    let full = self.full_key(sub_prefix);
+    let v = EtcdInst::request(format!("etcd kv delete prefix#{}", full), self.client.delete(DeleteRequest::new(KeyRange::prefix(full.as_str())))).await?;
+    Ok(v.deleted)
+  }
+
+  // writes data only while the key is still at expected_revision (its mod revision, 0 for "must not exist").
+  // the new revision, or None when someone else changed the key first
+  pub async fn compare_and_swap(&self, key : &str, expected_revision : i64, data : &T) -> types::Result<Option<i64>> {
+    let full = self.full_key(key);
+    let put = PutRequest::new(full.as_str(), Self::encode(&full, data)?);
+    let txn_request = if expected_revision == 0 {
+      TxnRequest::new().when_create_revision(KeyRange::key(full.as_str()), TxnCmp::Equal, 0)
+    } else {
+      TxnRequest::new().when_mod_revision(KeyRange::key(full.as_str()), TxnCmp::Equal, expected_revision as usize)
+    };
+    let v = EtcdInst::request(format!("etcd kv cas#{}", full), self.client.txn(txn_request.and_then(TxnOp::Put(put)))).await?;
+    if !v.succeeded {
+      debug!("etcd kv cas#{} lost, expected revision {}\n", full, expected_revision);
+      return Ok(None);
+    }
+    Ok(Some(v.header.revision()))
+  }
+
+  // follows changes under sub_prefix from start_revision (0 for now on). after a disconnect the watch
+  // resumes right after the last delivered revision, so no event is lost or repeated. when that
+  // revision was compacted away a Resync with the current entries is sent instead of the lost events
+  pub fn watch(&self, sub_prefix : &str, start_revision : i64) -> EtcdKvWatch<T> {
+    let (tx, rx) = mpsc::channel(ETCD_KV_WATCH_BUFFER);
+    let kv = self.clone();
+    let full = self.full_key(sub_prefix);
+    let task = tokio::spawn(async move {
+      kv.watch_loop(full, start_revision, tx).await;
+    });
+    EtcdKvWatch{
+      rx,
+      task,
+    }
+  }
+
+  async fn current_revision(&self) -> types::Result<i64> {
+    let req = RangeRequest::new(KeyRange::key(self.prefix.as_str())).limit(1);
+    let v = EtcdInst::request(format!("etcd kv revision#{}", self.prefix), self.client.get(req)).await?;
+    Ok(v.header.revision())
+  }
+
+  fn event(&self, ev : &Event) -> types::Result<EtcdKvEvent<T>> {
+    let prev = match ev.prev_kv.as_ref() {
+      Some(kv) => { Self::decode(kv.key_str(), &kv.value).ok() }
+      None => { None }
+    };
+    match ev.event_type {
+      EventType::Put => {
+        Ok(EtcdKvEvent::Put{
+          entry: Self::entry(&self.prefix, &ev.kv)?,
+          prev,
+        })
+      }
+      EventType::Delete => {
+        let key = ev.kv.key_str();
+        Ok(EtcdKvEvent::Delete{
+          key: key.strip_prefix(self.prefix.as_str()).unwrap_or(key).to_string(),
+          revision: ev.kv.mod_revision,
+          prev,
+        })
+      }
+    }
+  }
+
+  // lists until it works, sends the Resync and returns the revision to watch from next.
+  // None once the receiver is gone
+  async fn resync(&self, full : &str, tx : &mpsc::Sender<EtcdKvEvent<T>>) -> Option<i64> {
+    loop {
+      if tx.is_closed() {
+        return None;
+      }
+      match self.list_full(full).await {
+        Ok(range) => {
+          let revision = range.revision + 1;
+          info!("etcd kv watch#{} resync, {} entries at revision {}\n", full, range.entries.len(), range.revision);
+          if tx.send(EtcdKvEvent::Resync(range)).await.is_err() {
+            return None;
+          }
+          return Some(revision);
+        }
+        Err(e) => {
+          error!("etcd kv watch#{} resync failed, err {}\n", full, e);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+      }
+    }
+  }
+
+  async fn watch_loop(&self, full : String, start_revision : i64, tx : mpsc::Sender<EtcdKvEvent<T>>) {
+    // next revision to watch from
+    let mut revision = start_revision;
+    loop {
+      if tx.is_closed() {
+        info!("etcd kv watch#{} receiver dropped, stop\n", full);
+        return;
+      }
+      if revision <= 0 {
+        match self.current_revision().await {
+          Ok(v) => { revision = v + 1; }
+          Err(e) => {
+            error!("etcd kv watch#{} failed, err {}\n", full, e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+          }
+        }
+      }
+      let req = WatchCreateRequest::create(KeyRange::prefix(full.as_str()))
+          .start_revision(revision)
+          .prev_kv();
+      let (mut stream, canceler) = match self.client.watch(req).await {
+        Ok(v) => { v }
+        Err(e) => {
+          error!("etcd kv watch#{} from {} failed, err {}\n", full, revision, e);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+          continue;
+        }
+      };
+      loop {
+        match stream.inbound().await {
+          WatchInbound::Ready(resp) => {
+            for ev in resp.events.iter() {
+              revision = ev.kv.mod_revision + 1;
+              let event = match self.event(ev) {
+                Ok(v) => { v }
+                Err(e) => {
+                  error!("etcd kv watch#{} skip event, err {}\n", full, e);
+                  continue;
+                }
+              };
+              if tx.send(event).await.is_err() {
+                let _ = canceler.cancel().await;
+                return;
+              }
+            }
+          }
+          WatchInbound::Interrupted(e) => {
+            warn!("etcd kv watch#{} interrupted at {}, rewatch, err {}\n", full, revision, e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            break;
+          }
+          WatchInbound::Closed => {
+            // canceled by the server, usually because revision was compacted away
+            warn!("etcd kv watch#{} canceled at {}, resync\n", full, revision);
+            revision = match self.resync(&full, &tx).await {
+              Some(v) => { v }
+              None => { return; }
+            };
+            break;
+          }
+        }
+      }
+    }
+  }
+}
+
+// typed change events of EtcdKv::watch, dropping it stops the watch
+pub struct EtcdKvWatch<T> {
+  rx : mpsc::Receiver<EtcdKvEvent<T>>,
+  task : JoinHandle<()>,
+}
+
+impl<T> EtcdKvWatch<T> {
+  pub async fn next(&mut self) -> Option<EtcdKvEvent<T>> {
+    self.rx.recv().await
+  }
+}
+
+impl<T> Drop for EtcdKvWatch<T> {
+  fn drop(&mut self) {
+    self.task.abort();
+  }
+}
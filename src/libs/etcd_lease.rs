@@ -0,0 +1,250 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use etcd_rs::{Client, LeaseId, LeaseKeepAlive, LeaseOp};
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::libs::types;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::etcd_lock::{grant_lease, revoke_lease};
+
+// the signal hook exits 1s after SIGTERM, the lease has to be revoked before
+const LEASE_EXIT_CHECK : Duration = Duration::from_millis(200);
+
+type EtcdLeaseHandler = Arc<dyn Fn(LeaseId) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+enum LeaseRefresh {
+  // remaining ttl
+  Alive(Duration),
+  // transport or stream error, the lease may still be alive
+  Failed,
+  Expired,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EtcdLeaseState {
+  // ttl as reported by the last refresh
+  Alive { lease_id : LeaseId, ttl : Duration },
+  // expired, a new lease is being granted
+  Lost { lease_id : LeaseId },
+  // revoked on exit or by EtcdLease::revoke
+  Revoked,
+}
+
+// keeps one lease alive, refreshing at refresh_fraction of its ttl. an expired lease is replaced by a
+// new one, on_lost handlers run first so they can drop what hung on the old lease (locks, registrations)
+pub struct EtcdLeaseKeeper {
+  client : Client,
+  ttl : Duration,
+  refresh_fraction : f64,
+  on_lost : Vec<EtcdLeaseHandler>,
+}
+
+impl EtcdLeaseKeeper {
+  pub fn new(client : Client, ttl : Duration) -> Self {
+    Self{
+      client,
+      ttl,
+      refresh_fraction: 1.0 / 3.0,
+      on_lost: vec![],
+    }
+  }
+
+  // clamped to 0.1..0.9, lower survives more failed refreshes before the lease runs out
+  pub fn with_refresh_fraction(mut self, fraction : f64) -> Self {
+    self.refresh_fraction = fraction.clamp(0.1, 0.9);
+    self
+  }
+
+  pub fn on_lost<F, Fut>(mut self, handler : F) -> Self
+      where F : Fn(LeaseId) -> Fut + Send + Sync + 'static,
+            Fut : Future<Output = ()> + Send + 'static
+  {
+    self.on_lost.push(Arc::new(move |lease_id : LeaseId| {
+      Box::pin(handler(lease_id)) as Pin<Box<dyn Future<Output = ()> + Send>>
+    }));
+    self
+  }
+
+  // grants the first lease before returning, so EtcdLease::lease_id is set right away
+  pub async fn start(self) -> types::Result<EtcdLease> {
+    let lease_id = grant_lease(&self.client, self.ttl).await?;
+    info!("etcd lease {} granted, ttl {:?}\n", lease_id, self.ttl);
+    let (tx, rx) = watch::channel(EtcdLeaseState::Alive{ lease_id, ttl: self.ttl });
+    let client = self.client.clone();
+    let task = tokio::spawn(self.run(lease_id, tx));
+    Ok(EtcdLease{
+      client,
+      state: rx,
+      task,
+    })
+  }
+
+  fn refresh_interval(&self, ttl : Duration) -> Duration {
+    ttl.mul_f64(self.refresh_fraction).max(Duration::from_millis(100))
+  }
+
+  async fn run(self, mut lease_id : LeaseId, tx : watch::Sender<EtcdLeaseState>) {
+    let mut ttl = self.ttl;
+    let mut refreshed = Instant::now();
+    let mut lease_alive : Option<LeaseKeepAlive> = None;
+    let mut refresh = tokio::time::interval(self.refresh_interval(ttl));
+    refresh.tick().await;
+    let mut exit_check = tokio::time::interval(LEASE_EXIT_CHECK);
+    loop {
+      tokio::select! {
+        _ = exit_check.tick() => {
+          if get_app_instance().is_exiting().await {
+            info!("etcd lease {} revoked on exit\n", lease_id);
+            revoke_lease(&self.client, "lease keeper", lease_id).await;
+            tx.send_replace(EtcdLeaseState::Revoked);
+            return;
+          }
+        }
+        _ = refresh.tick() => {
+          match self.refresh(lease_id, &mut lease_alive).await {
+            LeaseRefresh::Alive(v) => {
+              refreshed = Instant::now();
+              if v != ttl {
+                ttl = v;
+                refresh = tokio::time::interval(self.refresh_interval(ttl));
+                refresh.tick().await;
+              }
+              tx.send_if_modified(|x| {
+                let alive = EtcdLeaseState::Alive{ lease_id, ttl };
+                if *x == alive {
+                  return false;
+                }
+                *x = alive;
+                true
+              });
+              continue;
+            }
+            LeaseRefresh::Failed => {
+              // only lost once its ttl ran out since the last good refresh
+              if refreshed.elapsed() < ttl {
+                continue;
+              }
+            }
+            LeaseRefresh::Expired => {}
+          }
+          warn!("etcd lease {} lost\n", lease_id);
+          tx.send_replace(EtcdLeaseState::Lost{ lease_id });
+          for handler in self.on_lost.iter() {
+            handler(lease_id).await;
+          }
+          lease_id = match self.regrant().await {
+            Some(v) => { v }
+            None => {
+              tx.send_replace(EtcdLeaseState::Revoked);
+              return;
+            }
+          };
+          ttl = self.ttl;
+          refreshed = Instant::now();
+          lease_alive = None;
+          refresh = tokio::time::interval(self.refresh_interval(ttl));
+          refresh.tick().await;
+          tx.send_replace(EtcdLeaseState::Alive{ lease_id, ttl });
+        }
+      }
+    }
+  }
+
+  async fn refresh(&self, lease_id : LeaseId, lease_alive : &mut Option<LeaseKeepAlive>) -> LeaseRefresh {
+    let alive = match lease_alive.as_mut() {
+      Some(v) => { v }
+      None => {
+        match self.client.keep_alive_for(lease_id).await {
+          Ok(v) => { lease_alive.insert(v) }
+          Err(e) => {
+            error!("etcd lease {} keep alive failed, err {}\n", lease_id, e);
+            return LeaseRefresh::Failed;
+          }
+        }
+      }
+    };
+    match alive.keep_alive().await {
+      Ok(Some(v)) => {
+        if v.ttl > 0 {
+          return LeaseRefresh::Alive(Duration::from_secs(v.ttl as u64));
+        }
+        // expired on the server, no point in waiting out the ttl
+        *lease_alive = None;
+        LeaseRefresh::Expired
+      }
+      Ok(None) => {
+        error!("etcd lease {} keep alive stream closed\n", lease_id);
+        *lease_alive = None;
+        LeaseRefresh::Failed
+      }
+      Err(e) => {
+        error!("etcd lease {} keep alive failed, err {}\n", lease_id, e);
+        *lease_alive = None;
+        LeaseRefresh::Failed
+      }
+    }
+  }
+
+  // None when the app started exiting before a lease was granted
+  async fn regrant(&self) -> Option<LeaseId> {
+    loop {
+      if get_app_instance().is_exiting().await {
+        return None;
+      }
+      match grant_lease(&self.client, self.ttl).await {
+        Ok(v) => {
+          info!("etcd lease {} granted, ttl {:?}\n", v, self.ttl);
+          return Some(v);
+        }
+        Err(e) => {
+          error!("etcd lease regrant failed, err {}\n", e);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+      }
+    }
+  }
+}
+
+// running keeper, dropping it stops refreshing and leaves the lease to its ttl
+pub struct EtcdLease {
+  client : Client,
+  state : watch::Receiver<EtcdLeaseState>,
+  task : JoinHandle<()>,
+}
+
+impl EtcdLease {
+  // None while the lease is lost or after it was revoked
+  pub fn lease_id(&self) -> Option<LeaseId> {
+    match *self.state.borrow() {
+      EtcdLeaseState::Alive { lease_id, .. } => { Some(lease_id) }
+      _ => { None }
+    }
+  }
+
+  pub fn state(&self) -> EtcdLeaseState {
+    self.state.borrow().clone()
+  }
+
+  pub fn subscribe(&self) -> watch::Receiver<EtcdLeaseState> {
+    self.state.clone()
+  }
+
+  // stops the keeper and revokes the lease, deleting every key bound to it
+  pub async fn revoke(self) {
+    self.task.abort();
+    if let Some(id) = self.lease_id() {
+      revoke_lease(&self.client, "lease keeper", id).await;
+      info!("etcd lease {} revoked\n", id);
+    }
+  }
+}
+
+impl Drop for EtcdLease {
+  fn drop(&mut self) {
+    self.task.abort();
+  }
+}
@@ -0,0 +1,281 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use etcd_rs::{Client, DeleteRequest, EventType, KeyRange, KeyValueOp, LeaseGrantRequest, LeaseId, LeaseKeepAlive, LeaseOp,
+              LeaseRevokeRequest, PutRequest, RangeRequest, TxnCmp, TxnOp, TxnOpResponse, TxnRequest, WatchCreateRequest,
+              WatchInbound, WatchOp};
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::etcd_impl::EtcdInst;
+use crate::libs::app::app_inst::get_app_instance;
+
+// how often a held lock looks for SIGTERM, well below the 1s the signal hook waits before exit
+const LOCK_EXIT_CHECK : Duration = Duration::from_millis(200);
+
+pub(crate) async fn grant_lease(client : &Client, ttl : Duration) -> types::Result<LeaseId> {
+  let v = EtcdInst::request("grant lease", client.grant_lease(LeaseGrantRequest::new(ttl))).await?;
+  Ok(v.id)
+}
+
+pub(crate) async fn revoke_lease(client : &Client, key : &str, lease_id : LeaseId) {
+  if let Err(e) = EtcdInst::request("revoke lease", client.revoke(LeaseRevokeRequest::new(lease_id))).await {
+    warn!("etcd#{} revoke lease {} failed, err {}\n", key, lease_id, e);
+  }
+}
+
+// deletes the key only while it is still the one we created, then drops the lease
+async fn release_key(client : &Client, key : &str, lease_id : LeaseId, fencing_token : i64) -> types::Result<bool> {
+  let txn_request = TxnRequest::new()
+      .when_create_revision(KeyRange::key(key), TxnCmp::Equal, fencing_token as usize)
+      .and_then(TxnOp::Delete(DeleteRequest::new(KeyRange::key(key))));
+  let r = EtcdInst::request(format!("etcd lock#{} release", key), client.txn(txn_request)).await?.succeeded;
+  revoke_lease(client, key, lease_id).await;
+  Ok(r)
+}
+
+// one keep alive round on lease_alive, opened on first use and reopened after stream errors.
+// false when etcd reports the lease expired, or no round succeeded for ttl since refreshed,
+// the time of the grant or of the last good round
+pub(crate) async fn refresh_lease(client : &Client, key : &str, lease_id : LeaseId, ttl : Duration, refreshed : &mut Instant,
+                                  lease_alive : &mut Option<LeaseKeepAlive>) -> bool {
+  let start = Instant::now();
+  let alive = match lease_alive.as_mut() {
+    Some(v) => { v }
+    None => {
+      match client.keep_alive_for(lease_id).await {
+        Ok(v) => { lease_alive.insert(v) }
+        Err(e) => {
+          error!("etcd#{} keep alive lease {} failed, err {}\n", key, lease_id, e);
+          return refreshed.elapsed() < ttl;
+        }
+      }
+    }
+  };
+  match alive.keep_alive().await {
+    Ok(Some(v)) => {
+      if v.ttl <= 0 {
+        return false;
+      }
+      *refreshed = start;
+      true
+    }
+    Ok(None) => {
+      error!("etcd#{} keep alive lease {} stream closed\n", key, lease_id);
+      *lease_alive = None;
+      refreshed.elapsed() < ttl
+    }
+    Err(e) => {
+      error!("etcd#{} keep alive lease {} failed, err {}\n", key, lease_id, e);
+      *lease_alive = None;
+      refreshed.elapsed() < ttl
+    }
+  }
+}
+
+// lock on an etcd key bound to a lease, the value is the owning app uuid.
+// the create revision of the key is handed out as fencing token, it only grows across holders
+pub struct EtcdLock {}
+
+impl EtcdLock {
+  pub async fn try_acquire(client : &Client, key : &str, ttl : Duration) -> types::Result<Option<EtcdLockGuard>> {
+    match Self::try_once(client, key, ttl).await? {
+      Ok(g) => { Ok(Some(g)) }
+      Err(_) => { Ok(None) }
+    }
+  }
+
+  // waits for the holder to go away via watch until wait elapses, None when still held by someone else
+  pub async fn acquire(client : &Client, key : &str, ttl : Duration, wait : Duration) -> types::Result<Option<EtcdLockGuard>> {
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+      let revision = match Self::try_once(client, key, ttl).await? {
+        Ok(g) => { return Ok(Some(g)); }
+        Err(v) => { v }
+      };
+      let now = tokio::time::Instant::now();
+      if now >= deadline {
+        return Ok(None);
+      }
+      if tokio::time::timeout(deadline - now, Self::wait_deleted(client, key, revision)).await.is_err() {
+        return Ok(None);
+      }
+    }
+  }
+
+  // the guard, or the revision the current holder was seen at
+  async fn try_once(client : &Client, key : &str, ttl : Duration) -> types::Result<Result<EtcdLockGuard, i64>> {
+    let owner = get_app_instance().get_application_uuid().await;
+    let lease_id = grant_lease(client, ttl).await?;
+    let txn_request = TxnRequest::new()
+        .when_create_revision(KeyRange::key(key), TxnCmp::Equal, 0)
+        .and_then(TxnOp::Put(PutRequest::new(key, owner.as_str()).lease(lease_id)))
+        .or_else(TxnOp::Range(RangeRequest::new(KeyRange::key(key))));
+    let resp = match EtcdInst::request(format!("etcd lock#{} acquire", key), client.txn(txn_request)).await {
+      Ok(v) => { v }
+      Err(e) => {
+        revoke_lease(client, key, lease_id).await;
+        return Err(e);
+      }
+    };
+    if resp.succeeded {
+      // the key was created by this txn, so its create revision is the txn revision
+      let fencing_token = resp.header.revision();
+      info!("etcd lock#{} acquired, owner {} fencing token {}\n", key, owner, fencing_token);
+      return Ok(Ok(EtcdLockGuard::new(client.clone(), key, lease_id, fencing_token, ttl)));
+    }
+    revoke_lease(client, key, lease_id).await;
+    for r in resp.responses.iter() {
+      if let TxnOpResponse::Range(v) = r {
+        if let Some(kv) = v.kvs.first() {
+          info!("etcd lock#{} held by {}\n", key, kv.value_str());
+        }
+      }
+    }
+    Ok(Err(resp.header.revision()))
+  }
+
+  // returns once the key is deleted after revision, or the watch ends
+  pub(crate) async fn wait_deleted(client : &Client, key : &str, revision : i64) -> types::Result<()> {
+    let req = WatchCreateRequest::create(KeyRange::key(key)).start_revision(revision + 1);
+    let (mut stream, canceler) = match client.watch(req).await {
+      Ok(v) => { v }
+      Err(e) => {
+        return Err(AppCommonError::etcd(format!("etcd lock#{} watch", key), e));
+      }
+    };
+    loop {
+      match stream.inbound().await {
+        WatchInbound::Ready(resp) => {
+          if resp.events.iter().any(|x| x.event_type == EventType::Delete) {
+            break;
+          }
+        }
+        WatchInbound::Interrupted(e) => {
+          // retry the txn, it rewatches from a fresh revision
+          warn!("etcd lock#{} watch interrupted, err {}\n", key, e);
+          break;
+        }
+        WatchInbound::Closed => { break; }
+      }
+    }
+    let _ = canceler.cancel().await;
+    Ok(())
+  }
+}
+
+// held lock, the lease is kept alive every ttl/3 while the guard lives. dropping the guard or the
+// app entering EXITING releases it from a spawned task, like RedisLockGuard
+pub struct EtcdLockGuard {
+  client : Client,
+  key : String,
+  lease_id : LeaseId,
+  fencing_token : i64,
+  held : Arc<AtomicBool>,
+  keeper : Option<JoinHandle<()>>,
+}
+
+impl EtcdLockGuard {
+  fn new(client : Client, key : &str, lease_id : LeaseId, fencing_token : i64, ttl : Duration) -> Self {
+    let held = Arc::new(AtomicBool::new(true));
+    let keeper = tokio::spawn(Self::keep_alive(client.clone(), key.to_string(), lease_id, fencing_token, ttl, held.clone()));
+    Self{
+      client,
+      key: key.to_string(),
+      lease_id,
+      fencing_token,
+      held,
+      keeper: Some(keeper),
+    }
+  }
+
+  async fn keep_alive(client : Client, key : String, lease_id : LeaseId, fencing_token : i64, ttl : Duration, held : Arc<AtomicBool>) {
+    let mut refresh = tokio::time::interval((ttl / 3).max(Duration::from_millis(100)));
+    // the lease was just granted, the first tick fires right away
+    refresh.tick().await;
+    let mut exit_check = tokio::time::interval(LOCK_EXIT_CHECK);
+    let mut refreshed = Instant::now();
+    let mut lease_alive : Option<LeaseKeepAlive> = None;
+    loop {
+      tokio::select! {
+        _ = exit_check.tick() => {
+          if get_app_instance().is_exiting().await {
+            info!("etcd lock#{} released on exit\n", key);
+            held.store(false, Ordering::SeqCst);
+            if let Err(e) = release_key(&client, &key, lease_id, fencing_token).await {
+              error!("etcd lock#{} release on exit failed, err {}\n", key, e);
+            }
+            return;
+          }
+        }
+        _ = refresh.tick() => {
+          if !refresh_lease(&client, &key, lease_id, ttl, &mut refreshed, &mut lease_alive).await {
+            warn!("etcd lock#{} lost, lease {} expired\n", key, lease_id);
+            held.store(false, Ordering::SeqCst);
+            return;
+          }
+        }
+      }
+    }
+  }
+
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+
+  pub fn lease_id(&self) -> LeaseId {
+    self.lease_id
+  }
+
+  // create revision of the lock key, pass it to the guarded resource so it can reject stale holders
+  pub fn fencing_token(&self) -> i64 {
+    self.fencing_token
+  }
+
+  // false once the lease expired or the app started exiting
+  pub fn is_held(&self) -> bool {
+    self.held.load(Ordering::SeqCst)
+  }
+
+  pub async fn release(mut self) -> types::Result<bool> {
+    if let Some(k) = self.keeper.take() {
+      k.abort();
+    }
+    self.held.store(false, Ordering::SeqCst);
+    let r = release_key(&self.client, &self.key, self.lease_id, self.fencing_token).await;
+    info!("etcd lock#{} released, result {:?}\n", self.key, r);
+    r
+  }
+}
+
+impl Drop for EtcdLockGuard {
+  fn drop(&mut self) {
+    let keeper = match self.keeper.take() {
+      Some(v) => { v }
+      None => {
+        // released explicitly
+        return;
+      }
+    };
+    keeper.abort();
+    self.held.store(false, Ordering::SeqCst);
+    let client = self.client.clone();
+    let key = self.key.clone();
+    let lease_id = self.lease_id;
+    let fencing_token = self.fencing_token;
+    match tokio::runtime::Handle::try_current() {
+      Ok(h) => {
+        h.spawn(async move {
+          if let Err(e) = release_key(&client, &key, lease_id, fencing_token).await {
+            error!("etcd lock#{} release on drop failed, err {}\n", key, e);
+          }
+        });
+      }
+      Err(_) => {
+        warn!("etcd lock#{} dropped outside runtime, left to the lease ttl\n", key);
+      }
+    }
+  }
+}
@@ -2,39 +2,38 @@ use tokio::sync::Mutex;
 use std::sync::{Arc};
 use log::{error};
 use lazy_static::lazy_static;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
 
-pub async fn reqwest_post(url : String, body : String, read_body : bool) -> Result<(u16, String), reqwest::Error> {
+pub async fn reqwest_post(url : String, body : String, read_body : bool) -> types::Result<(u16, String)> {
     let client = get_client().get().await;
-    let response = client
-        .post(url)
+    let response = match client
+        .post(&url)
         .body(body)
         .version(reqwest::Version::HTTP_2)
         .send()
-        .await?;
+        .await {
+        Ok(v) => { v }
+        Err(e) => {
+            return Err(AppCommonError::http(format!("post {}", url), e));
+        }
+    };
     let status_code = response.status().as_u16();
     if read_body {
-        let resp_json: serde_json::Value = response
+        let resp_json: serde_json::Value = match response
             .json()
-            .await?;
+            .await {
+            Ok(v) => { v }
+            Err(e) => {
+                return Err(AppCommonError::http(format!("read body {}", url), e));
+            }
+        };
         Ok((status_code, resp_json.to_string()))
     } else {
         Ok((status_code, "{}".to_string()))
     }
 }
 
-pub async fn http2_client_post(url : String, body : String, read_body : bool) -> (u16, String) {
-    let x = reqwest_post(url.clone(), body.clone(), read_body).await;
-    match x {
-        Ok((status, body)) => {
-            return (status, body);
-        }
-        Err(e) => {
-            error!("reqwest {} failed, err {}\n", url, e);
-        }
-    }
-    return (0, "".to_string());
-}
-
 const HTTP2_CLIENTS_POOL_SIZE  : usize = 1;
 
 struct Http2ClientPoolClients {
@@ -1,31 +1,47 @@
 extern crate serde_json;
 
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::libs::error::AppCommonError;
+use crate::libs::types;
 
-pub fn marshal<T>(data : &T) -> Result<String, Box<dyn std::error::Error + Send + Sync>>
+pub fn marshal<T>(data : &T) -> types::Result<String>
     where T : Serialize
 {
     match serde_json::to_string_pretty(&data) {
         Ok(t) => {
             Ok(t)
         }
-        _ => {
-            Err("to string failed")?
+        Err(e) => {
+            Err(AppCommonError::json("marshal", e))
         }
     }
 }
 
-pub fn unmarshal<'de, T>(stx: &'de String, data : &mut T) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+pub fn unmarshal<'de, T>(stx: &'de str, data : &mut T) -> types::Result<()>
        where T : Deserialize<'de>
 {
-    let r: serde_json::Result<T> = serde_json::from_str(stx.as_str());
+    let r: serde_json::Result<T> = serde_json::from_str(stx);
     return match r {
         Ok(v) => {
             *data = v;
             Ok(())
         }
         Err(e) => {
-            Err(Box::try_from(e).unwrap())
+            Err(AppCommonError::json("unmarshal", e))
+        }
+    }
+}
+
+pub fn unmarshal_value<T>(stx: &str) -> types::Result<T>
+       where T : DeserializeOwned
+{
+    match serde_json::from_str(stx) {
+        Ok(v) => {
+            Ok(v)
+        }
+        Err(e) => {
+            Err(AppCommonError::json("unmarshal", e))
         }
     }
-}
\ No newline at end of file
+}
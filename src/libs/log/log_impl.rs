@@ -19,6 +19,8 @@ use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::filter::threshold::ThresholdFilter;
 use tokio::sync::Mutex;
 use crate::libs::utility;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
 
 pub struct SysLogger {
     handle : Mutex<Option<Handle>>,
@@ -58,8 +60,8 @@ impl SysLogger {
         }
     }
 
-    pub async fn init_log(&self, log_name : &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let config = self.build_config(log_name, LevelFilter::Trace).unwrap();
+    pub async fn init_log(&self, log_name : &str) -> types::Result<()> {
+        let config = self.build_config(log_name, LevelFilter::Trace)?;
         return match log4rs::init_config(config) {
             Ok(v) => {
                 let mut x = self.handle.lock().await;
@@ -67,35 +69,42 @@ impl SysLogger {
                 Ok(())
             }
             Err(e) => {
-                Err(format!("{}\n", e.to_string()))?
+                Err(AppCommonError::log(format!("init log {}", log_name), e))
             }
         }
     }
 
-    fn build_config(&self, log_name : &str, log_level : LevelFilter) -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
+    fn build_config(&self, log_name : &str, log_level : LevelFilter) -> types::Result<Config> {
         let home = utility::get_home();
         let file_path = format!("{}/log/{}.log", home, log_name);
         let file_path_roll = format!("{}/log/{}{{}}.log", home, log_name);
 
         let window_size = 20;
-        let fixed_window_roller = FixedWindowRoller::builder().build(&file_path_roll,window_size).unwrap();
+        let fixed_window_roller = match FixedWindowRoller::builder().build(&file_path_roll,window_size) {
+            Ok(v) => { v }
+            Err(e) => {
+                return Err(AppCommonError::log(format!("build roller {}", file_path_roll), e));
+            }
+        };
 
         let size_limit = 500 * 1024 * 1024;
         let size_trigger = SizeTrigger::new(size_limit);
         let compound_policy = CompoundPolicy::new(Box::new(size_trigger),Box::new(fixed_window_roller));
 
+        let rolling_file = match RollingFileAppender::builder()
+            .encoder(Box::new(PatternEncoder::new("{d} {l} {M}:{L} - {m}{n}")))
+            .build(&file_path, Box::new(compound_policy)) {
+            Ok(v) => { v }
+            Err(e) => {
+                return Err(AppCommonError::log(format!("build appender {}", file_path), e));
+            }
+        };
+
         match Config::builder()
             .appender(
                 Appender::builder()
                     .filter(Box::new(ThresholdFilter::new(log_level)))
-                    .build(
-                        "logfile",
-                        Box::new(
-                            RollingFileAppender::builder()
-                                .encoder(Box::new(PatternEncoder::new("{d} {l} {M}:{L} - {m}{n}")))
-                                .build(file_path, Box::new(compound_policy))?,
-                        ),
-                    ),
+                    .build("logfile", Box::new(rolling_file)),
             )
             .build(
                 Root::builder()
@@ -106,7 +115,7 @@ impl SysLogger {
                 Ok(v)
             }
             Err(e) => {
-                Err(format!("{}\n", e.to_string()))?
+                Err(AppCommonError::log("build log config", e))
             }
         }
     }
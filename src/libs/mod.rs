@@ -1,10 +1,28 @@
 pub mod config;
 pub mod types;
+pub mod error;
 pub mod redis_impl;
+pub mod redis_codec;
 pub mod json;
 pub mod redis_pool;
+pub mod redis_async_pool;
+pub mod redis_repository;
+pub mod redis_pipeline;
+pub mod redis_script;
+pub mod redis_lock;
+pub mod redis_stream;
+pub mod redis_pubsub;
+pub mod redis_reliable_queue;
+pub mod redis_scheduler;
+pub mod redis_rate_limiter;
+pub mod redis_cache;
 pub mod utility;
 pub mod etcd_impl;
+pub mod etcd_lock;
+pub mod etcd_election;
+pub mod etcd_lease;
+pub mod etcd_kv;
+pub mod config_etcd;
 pub mod defer;
 pub mod log;
 pub mod app;
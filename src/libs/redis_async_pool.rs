@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use redis::{Cmd, Pipeline, RedisConnectionInfo, RedisFuture, TlsMode, Value};
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{Sentinel, SentinelNodeConnectionInfo};
+use tokio::sync::Mutex;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::config::{get_config, strip_credentials, Redis, RedisTopology};
+
+// connection handed out by the async pool, every RedisOp call runs on it.
+// both variants are multiplexed, clones share the socket
+#[derive(Clone)]
+pub enum RedisConnection {
+    Cluster(ClusterConnection),
+    // standalone node or the primary a sentinel pointed to
+    Node(MultiplexedConnection),
+}
+
+impl RedisConnection {
+    pub fn is_cluster(&self) -> bool {
+        matches!(self, Self::Cluster(_))
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd : &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Cluster(c) => { c.req_packed_command(cmd) }
+            Self::Node(c) => { c.req_packed_command(cmd) }
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd : &'a Pipeline, offset : usize, count : usize) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Cluster(c) => { c.req_packed_commands(cmd, offset, count) }
+            Self::Node(c) => { c.req_packed_commands(cmd, offset, count) }
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Cluster(c) => { c.get_db() }
+            Self::Node(c) => { c.get_db() }
+        }
+    }
+}
+
+// the primary is reached with the tls setting and credentials of the first [[redis]] entry
+fn sentinel_node_info(node : Option<&Redis>) -> SentinelNodeConnectionInfo {
+    let node = match node {
+        Some(v) => { v }
+        None => {
+            return Default::default();
+        }
+    };
+    let tls_mode = match (node.tls, node.tls_insecure) {
+        (false, _) => { None }
+        (true, false) => { Some(TlsMode::Secure) }
+        (true, true) => { Some(TlsMode::Insecure) }
+    };
+    SentinelNodeConnectionInfo{
+        tls_mode,
+        redis_connection_info: Some(RedisConnectionInfo{
+            db: node.db,
+            username: node.username.clone(),
+            password: node.password.clone(),
+        }),
+    }
+}
+
+async fn sentinel_primary(uri : &[String], master : &str) -> types::Result<redis::Client> {
+    let info = sentinel_node_info(get_config().lock().await.get_redis_nodes().first());
+    let mut sentinel = match Sentinel::build(uri.to_vec()) {
+        Ok(v) => { v }
+        Err(e) => {
+            return Err(AppCommonError::redis("open sentinel", e));
+        }
+    };
+    match sentinel.async_master_for(master, Some(&info)).await {
+        Ok(v) => {
+            info!("sentinel primary of {} : {}\n", master, v.get_connection_info().addr);
+            Ok(v)
+        }
+        Err(e) => {
+            Err(AppCommonError::redis(format!("sentinel resolve {}", master), e))
+        }
+    }
+}
+
+// client of a single data node, for what the cluster client can't do (pub/sub, per node scans).
+// idx picks the node, a sentinel setup always answers with the current primary
+pub async fn node_client(uri : &[String], idx : usize) -> types::Result<redis::Client> {
+    if uri.is_empty() {
+        return Err(AppCommonError::redis_msg("no redis nodes configured"));
+    }
+    let (topology, master) = {
+        let c = get_config().lock().await;
+        (c.get_redis_topology(), c.get_redis_sentinel_master())
+    };
+    if topology == RedisTopology::Sentinel {
+        if master.is_empty() {
+            return Err(AppCommonError::config_msg("redis_sentinel_master is required for the sentinel topology"));
+        }
+        return sentinel_primary(uri, &master).await;
+    }
+    let node = &uri[idx % uri.len()];
+    match redis::Client::open(node.as_str()) {
+        Ok(v) => { Ok(v) }
+        Err(e) => {
+            Err(AppCommonError::redis(format!("open {}", strip_credentials(node)), e))
+        }
+    }
+}
+
+// one connection for the configured topology
+pub async fn open_connection(uri : &[String]) -> types::Result<RedisConnection> {
+    let topology = get_config().lock().await.get_redis_topology();
+    match topology {
+        RedisTopology::Cluster => {
+            let client = match ClusterClient::new(uri.to_vec()) {
+                Ok(v) => { v }
+                Err(e) => {
+                    return Err(AppCommonError::redis("open cluster client", e));
+                }
+            };
+            match client.get_async_connection().await {
+                Ok(v) => { Ok(RedisConnection::Cluster(v)) }
+                Err(e) => {
+                    Err(AppCommonError::redis("connect", e))
+                }
+            }
+        }
+        RedisTopology::Standalone | RedisTopology::Sentinel => {
+            let mut last = AppCommonError::redis_msg("no redis nodes configured");
+            let tries = if topology == RedisTopology::Sentinel { 1 } else { uri.len() };
+            for idx in 0..tries {
+                let client = node_client(uri, idx).await?;
+                match client.get_multiplexed_async_connection().await {
+                    Ok(v) => {
+                        return Ok(RedisConnection::Node(v));
+                    }
+                    Err(e) => {
+                        warn!("redis connect {} failed, err {}\n", client.get_connection_info().addr, e);
+                        last = AppCommonError::redis("connect", e);
+                    }
+                }
+            }
+            Err(last)
+        }
+    }
+}
+
+struct RedisAsyncPoolConnections {
+    pool : Vec<RedisConnection>,
+    idx : usize,
+    uri : Vec<String>,
+}
+
+impl RedisAsyncPoolConnections {
+    fn get(&mut self) -> RedisConnection {
+        if self.idx >= self.pool.len() {
+            self.idx = 0;
+        }
+        let ixx = self.idx;
+        self.idx += 1;
+        self.pool[ixx].clone()
+    }
+}
+
+async fn build_pool(uri : Vec<String>, pool_size : u32) -> types::Result<Vec<RedisConnection>> {
+    let mut pool = vec![];
+    for _ in 0..pool_size {
+        pool.push(open_connection(&uri).await?);
+    }
+    Ok(pool)
+}
+
+// every connection is multiplexed, so the pool only spreads load over a few
+// sockets per node, handing out a clone never waits for another caller.
+// blocking commands (br_pop) stall the whole socket, use RedisOp::connect for them
+pub struct RedisAsyncPool {
+    connections : Mutex<Option<RedisAsyncPoolConnections>>,
+    // one connection per cluster node address, for the commands the cluster client can't route
+    nodes : Mutex<HashMap<String, MultiplexedConnection>>,
+}
+
+impl RedisAsyncPool {
+    const MAX_REDIS_ASYNC_POOL : u32 = 128;
+    // sockets opened when the pool is built on first use, each one is multiplexed
+    const DEFAULT_REDIS_ASYNC_POOL : u32 = 4;
+    pub fn new() -> Self {
+        Self{
+            connections: Default::default(),
+            nodes: Default::default(),
+        }
+    }
+    pub async fn init_pool(&self, uri : Vec<String>, pool_size : u32) -> types::Result<()> {
+        let size = pool_size.clamp(1, Self::MAX_REDIS_ASYNC_POOL);
+        let nodes : Vec<String> = uri.iter().map(|x| strip_credentials(x)).collect();
+        info!("redis async pool nodes : {:?}, size {}\n", nodes, size);
+        let x = build_pool(uri.clone(), size).await?;
+        self.nodes.lock().await.clear();
+        let mut inner = self.connections.lock().await;
+        *inner = Some(RedisAsyncPoolConnections{
+            pool: x,
+            idx: 0,
+            uri,
+        });
+        Ok(())
+    }
+    // reconnects every pooled connection, e.g. after a sentinel failover moved the primary
+    // reconnects to uri with the current pool size
+    pub async fn rebuild(&self, uri : Vec<String>) -> types::Result<()> {
+        let size = match self.connections.lock().await.as_ref() {
+            Some(v) => { v.pool.len() as u32 }
+            None => {
+                return Err(AppCommonError::redis_msg("redis async pool not initialized"));
+            }
+        };
+        self.init_pool(uri, size).await
+    }
+
+    pub async fn nodes(&self) -> Vec<String> {
+        match self.connections.lock().await.as_ref() {
+            Some(v) => { v.uri.clone() }
+            None => { vec![] }
+        }
+    }
+    // builds the pool from the [[redis]] nodes when nobody called init_pool before
+    // connects without holding the lock, so callers of an existing pool never wait on it.
+    // when two first uses race the pool published first wins and the other is dropped
+    pub async fn get_or_init(&self) -> types::Result<RedisConnection> {
+        if let Some(v) = self.connections.lock().await.as_mut() {
+            return Ok(v.get());
+        }
+        let uri = get_config().lock().await.get_redis_config();
+        let size = Self::DEFAULT_REDIS_ASYNC_POOL;
+        let pool = build_pool(uri.clone(), size).await?;
+        let mut inner = self.connections.lock().await;
+        if inner.is_none() {
+            info!("redis async pool built on first use, size {}\n", size);
+            *inner = Some(RedisAsyncPoolConnections{
+                pool,
+                idx: 0,
+                uri,
+            });
+        }
+        match inner.as_mut() {
+            Some(v) => { Ok(v.get()) }
+            None => {
+                Err(AppCommonError::redis_msg("redis async pool not initialized"))
+            }
+        }
+    }
+    // cached connection to one node (host:port) of the cluster, opened on first use
+    pub async fn node_connection(&self, addr : &str) -> types::Result<MultiplexedConnection> {
+        if let Some(c) = self.nodes.lock().await.get(addr) {
+            return Ok(c.clone());
+        }
+        let uri = get_config().lock().await.get_redis_node_uri(addr);
+        let client = match redis::Client::open(uri) {
+            Ok(v) => { v }
+            Err(e) => {
+                return Err(AppCommonError::redis(format!("open {}", addr), e));
+            }
+        };
+        let c = match client.get_multiplexed_async_connection().await {
+            Ok(v) => { v }
+            Err(e) => {
+                return Err(AppCommonError::redis(format!("connect {}", addr), e));
+            }
+        };
+        // a concurrent caller may have connected first, keep its connection
+        Ok(self.nodes.lock().await.entry(addr.to_string()).or_insert(c).clone())
+    }
+
+    // drops the cached connection of addr after it failed, the next use reconnects
+    pub async fn forget_node_connection(&self, addr : &str) {
+        self.nodes.lock().await.remove(addr);
+    }
+
+    pub async fn get(&self) -> types::Result<RedisConnection> {
+        let mut inner = self.connections.lock().await;
+        match inner.as_mut() {
+            Some(v) => {
+                Ok(v.get())
+            }
+            None => {
+                Err(AppCommonError::redis_msg("redis async pool not initialized"))
+            }
+        }
+    }
+}
+
+impl Default for RedisAsyncPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+lazy_static!(
+  static ref REDIS_ASYNC_POOL_INSTANCE : RedisAsyncPool = RedisAsyncPool::new();
+);
+
+pub fn get_redis_async_pool() -> &'static RedisAsyncPool { &REDIS_ASYNC_POOL_INSTANCE }
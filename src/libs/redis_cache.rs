@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use rand::Rng;
+use redis::{AsyncCommands, RedisResult};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::redis_codec;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_lock::RedisLock;
+
+#[derive(Debug, Clone)]
+pub struct RedisCacheConfig {
+    // default expiry of cached values
+    pub ttl : Duration,
+    // every expiry is extended by a random 0..jitter fraction, so keys written together don't expire together
+    pub jitter : f64,
+    // single flight lock held by the loading instance
+    pub lock_ttl : Duration,
+    // how long other callers wait for the loader before computing on their own
+    pub lock_wait : Duration,
+    // entries of the in-process cache, 0 disables it
+    pub l1_capacity : usize,
+    pub l1_ttl : Duration,
+}
+
+impl Default for RedisCacheConfig {
+    fn default() -> Self {
+        Self{
+            ttl: Duration::from_secs(300),
+            jitter: 0.1,
+            lock_ttl: Duration::from_secs(10),
+            lock_wait: Duration::from_secs(5),
+            l1_capacity: 0,
+            l1_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+// small lru of encoded values, tick orders entries by last use
+#[derive(Default)]
+struct RedisCacheLru {
+    capacity : usize,
+    tick : u64,
+    entries : HashMap<String, (Vec<u8>, Instant, u64)>,
+    order : BTreeMap<u64, String>,
+}
+
+impl RedisCacheLru {
+    fn new(capacity : usize) -> Self {
+        Self{
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, key : &str) -> Option<Vec<u8>> {
+        let (value, expire, used) = self.entries.get(key)?.clone();
+        if expire <= Instant::now() {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        self.order.remove(&used);
+        self.order.insert(self.tick, key.to_string());
+        self.entries.insert(key.to_string(), (value.clone(), expire, self.tick));
+        Some(value)
+    }
+
+    fn put(&mut self, key : &str, value : Vec<u8>, ttl : Duration) {
+        self.remove(key);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.pop_first() {
+                Some((_, k)) => { k }
+                None => { break; }
+            };
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_string());
+        self.entries.insert(key.to_string(), (value, Instant::now() + ttl, self.tick));
+    }
+
+    fn remove(&mut self, key : &str) {
+        if let Some((_, _, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+}
+
+// read-through cache, values use the redis_codec chosen for T. get_or_compute() lets one caller
+// across all instances run the loader for a missing key (RedisLock on <key>:loading),
+// the others wait for its result
+pub struct RedisCache {
+    conn : RedisConnection,
+    prefix : String,
+    config : RedisCacheConfig,
+    l1 : Option<std::sync::Mutex<RedisCacheLru>>,
+}
+
+impl RedisCache {
+    pub fn new(conn : RedisConnection, prefix : &str, config : RedisCacheConfig) -> Self {
+        let l1 = if config.l1_capacity > 0 {
+            Some(std::sync::Mutex::new(RedisCacheLru::new(config.l1_capacity)))
+        } else {
+            None
+        };
+        Self{
+            conn,
+            prefix: prefix.to_string(),
+            config,
+            l1,
+        }
+    }
+
+    fn cache_key(&self, key : &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+
+    fn jittered(&self, ttl : Duration) -> Duration {
+        if self.config.jitter <= 0.0 {
+            return ttl;
+        }
+        let f = rand::thread_rng().gen_range(0.0..=self.config.jitter);
+        ttl + ttl.mul_f64(f)
+    }
+
+    fn l1_get(&self, key : &str) -> Option<Vec<u8>> {
+        let l1 = self.l1.as_ref()?;
+        match l1.lock() {
+            Ok(mut x) => { x.get(key) }
+            Err(_) => { None }
+        }
+    }
+
+    fn l1_put(&self, key : &str, value : &[u8], ttl : Duration) {
+        if let Some(l1) = self.l1.as_ref() {
+            if let Ok(mut x) = l1.lock() {
+                x.put(key, value.to_vec(), ttl.min(self.config.l1_ttl));
+            }
+        }
+    }
+
+    fn l1_remove(&self, key : &str) {
+        if let Some(l1) = self.l1.as_ref() {
+            if let Ok(mut x) = l1.lock() {
+                x.remove(key);
+            }
+        }
+    }
+
+    async fn get_raw(&self, key : &str) -> types::Result<Option<Vec<u8>>> {
+        if let Some(v) = self.l1_get(key) {
+            return Ok(Some(v));
+        }
+        let mut c = self.conn.clone();
+        let r : RedisResult<Option<Vec<u8>>> = c.get(key).await;
+        match r {
+            Ok(Some(v)) => {
+                // the l1 copy lives at most l1_ttl, the remote ttl isn't worth a round trip
+                self.l1_put(key, &v, self.config.l1_ttl);
+                Ok(Some(v))
+            }
+            Ok(None) => { Ok(None) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("cache get#{}", key), x))
+            }
+        }
+    }
+
+    pub async fn get<T>(&self, key : &str) -> types::Result<Option<T>>
+        where T : DeserializeOwned
+    {
+        let key = self.cache_key(key);
+        match self.get_raw(&key).await? {
+            Some(raw) => {
+                match redis_codec::decode_value(&raw) {
+                    Ok(v) => { Ok(Some(v)) }
+                    Err(e) => {
+                        // stale layout after a type change, treat as a miss
+                        warn!("cache decode#{} failed, err {}\n", key, e);
+                        self.l1_remove(&key);
+                        Ok(None)
+                    }
+                }
+            }
+            None => { Ok(None) }
+        }
+    }
+
+    pub async fn set<T>(&self, key : &str, data : &T, ttl : Option<Duration>) -> types::Result<()>
+        where T : Debug + Serialize
+    {
+        let key = self.cache_key(key);
+        let ttl = self.jittered(ttl.unwrap_or(self.config.ttl));
+        debug!("cache set#{} ttl {:?} : {:?}\n", key, ttl, data);
+        let j = redis_codec::encode(data)?;
+        let mut c = self.conn.clone();
+        let r : RedisResult<()> = redis::cmd("SET")
+            .arg(&key)
+            .arg(&j)
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .query_async(&mut c)
+            .await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("cache set#{}", key), x));
+        }
+        self.l1_put(&key, &j, ttl);
+        Ok(())
+    }
+
+    // drops the shared value and this instance's l1 copy, other instances keep theirs up to l1_ttl
+    pub async fn invalidate(&self, key : &str) -> types::Result<()> {
+        let key = self.cache_key(key);
+        self.l1_remove(&key);
+        let mut c = self.conn.clone();
+        let r : RedisResult<i64> = c.del(&key).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("cache invalidate#{}", key), x));
+        }
+        Ok(())
+    }
+
+    // cached value, or the loader's result stored with ttl (config ttl when None).
+    // loader errors are returned as is and nothing is cached
+    pub async fn get_or_compute<T, F, Fut>(&self, key : &str, ttl : Option<Duration>, loader : F) -> types::Result<T>
+        where T : Debug + Serialize + DeserializeOwned,
+              F : FnOnce() -> Fut,
+              Fut : Future<Output = types::Result<T>>
+    {
+        if let Some(v) = self.get(key).await? {
+            return Ok(v);
+        }
+        let lock_key = format!("{}:loading", self.cache_key(key));
+        let guard = RedisLock::acquire(&self.conn, &lock_key, self.config.lock_ttl, self.config.lock_wait).await?;
+        if guard.is_some() {
+            // the previous holder may have filled it while we waited
+            if let Some(v) = self.get(key).await? {
+                return Ok(v);
+            }
+        } else {
+            if let Some(v) = self.get(key).await? {
+                return Ok(v);
+            }
+            warn!("cache load#{} still running elsewhere after {:?}, load here\n", key, self.config.lock_wait);
+        }
+        let v = loader().await?;
+        self.set(key, &v, ttl).await?;
+        if let Some(g) = guard {
+            if let Err(e) = g.release().await {
+                warn!("cache load#{} release lock failed, err {}\n", key, e);
+            }
+        }
+        Ok(v)
+    }
+}
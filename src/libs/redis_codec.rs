@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use crate::libs::types;
+use crate::libs::error::{AppCommonError, BoxError};
+
+// binary values start with MAGIC, format, compression. 0xC1 is neither valid utf-8 nor a
+// msgpack marker, so anything else is json (pretty values of older releases included)
+const REDIS_CODEC_MAGIC : u8 = 0xC1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedisFormat {
+    PrettyJson,
+    Json,
+    // named fields, so struct changes stay readable like json
+    MessagePack,
+    // smallest and fastest, but positional and without deserialize_any (no untagged enums, serde_json::Value)
+    Bincode,
+}
+
+impl RedisFormat {
+    fn id(&self) -> u8 {
+        match self {
+            Self::PrettyJson | Self::Json => { 0 }
+            Self::MessagePack => { 1 }
+            Self::Bincode => { 2 }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedisCompression {
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl RedisCompression {
+    fn id(&self) -> u8 {
+        match self {
+            Self::None => { 0 }
+            #[cfg(feature = "zstd")]
+            Self::Zstd(_) => { 1 }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => { 2 }
+        }
+    }
+
+    fn compress(&self, data : Vec<u8>) -> types::Result<Vec<u8>> {
+        match self {
+            Self::None => { Ok(data) }
+            #[cfg(feature = "zstd")]
+            Self::Zstd(level) => {
+                match zstd::bulk::compress(&data, *level) {
+                    Ok(v) => { Ok(v) }
+                    Err(e) => {
+                        Err(AppCommonError::codec("zstd compress", e))
+                    }
+                }
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => { Ok(lz4_flex::compress_prepend_size(&data)) }
+        }
+    }
+}
+
+fn json_result<V, E>(context : &str, r : Result<V, E>) -> types::Result<V>
+    where E : Into<BoxError>
+{
+    match r {
+        Ok(v) => { Ok(v) }
+        Err(e) => {
+            Err(AppCommonError::json(context, e))
+        }
+    }
+}
+
+fn codec_result<V, E>(context : &str, r : Result<V, E>) -> types::Result<V>
+    where E : Into<BoxError>
+{
+    match r {
+        Ok(v) => { Ok(v) }
+        Err(e) => {
+            Err(AppCommonError::codec(context, e))
+        }
+    }
+}
+
+fn decompress(id : u8, data : &[u8]) -> types::Result<Vec<u8>> {
+    match id {
+        0 => { Ok(data.to_vec()) }
+        #[cfg(feature = "zstd")]
+        1 => {
+            match zstd::stream::decode_all(data) {
+                Ok(v) => { Ok(v) }
+                Err(e) => {
+                    Err(AppCommonError::codec("zstd decompress", e))
+                }
+            }
+        }
+        #[cfg(feature = "lz4")]
+        2 => {
+            match lz4_flex::decompress_size_prepended(data) {
+                Ok(v) => { Ok(v) }
+                Err(e) => {
+                    Err(AppCommonError::codec("lz4 decompress", e))
+                }
+            }
+        }
+        _ => {
+            Err(AppCommonError::codec_msg(format!("compression {} not built in, enable its cargo feature", id)))
+        }
+    }
+}
+
+// how values are written, reading detects the format on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedisCodec {
+    pub format : RedisFormat,
+    pub compression : RedisCompression,
+}
+
+impl Default for RedisCodec {
+    fn default() -> Self {
+        Self{
+            format: RedisFormat::Json,
+            compression: RedisCompression::None,
+        }
+    }
+}
+
+impl RedisCodec {
+    pub fn new(format : RedisFormat, compression : RedisCompression) -> Self {
+        Self{
+            format,
+            compression,
+        }
+    }
+
+    pub fn encode<T>(&self, data : &T) -> types::Result<Vec<u8>>
+        where T : Serialize + ?Sized
+    {
+        let payload = match self.format {
+            RedisFormat::PrettyJson => { json_result("marshal", serde_json::to_vec_pretty(data))? }
+            RedisFormat::Json => { json_result("marshal", serde_json::to_vec(data))? }
+            RedisFormat::MessagePack => { codec_result("msgpack encode", rmp_serde::to_vec_named(data))? }
+            RedisFormat::Bincode => { codec_result("bincode encode", bincode::serialize(data))? }
+        };
+        if self.format.id() == 0 && self.compression == RedisCompression::None {
+            // plain json stays readable for redis-cli and other services
+            return Ok(payload);
+        }
+        let mut out = vec![REDIS_CODEC_MAGIC, self.format.id(), self.compression.id()];
+        out.extend(self.compression.compress(payload)?);
+        Ok(out)
+    }
+}
+
+// decodes any value written by encode() or the old pretty json. a compressed value is
+// replaced by its plain payload in raw, so T may borrow from it
+pub fn decode<'de, T>(raw : &'de mut Vec<u8>) -> types::Result<T>
+    where T : Deserialize<'de>
+{
+    if raw.len() < 3 || raw[0] != REDIS_CODEC_MAGIC {
+        return json_result("unmarshal", serde_json::from_slice(raw));
+    }
+    let format = raw[1];
+    if raw[2] != 0 {
+        let plain = decompress(raw[2], &raw[3..])?;
+        raw.truncate(3);
+        raw.extend(plain);
+    }
+    let raw : &'de Vec<u8> = raw;
+    let payload = &raw[3..];
+    match format {
+        0 => { json_result("unmarshal", serde_json::from_slice(payload)) }
+        1 => { codec_result("msgpack decode", rmp_serde::from_slice(payload)) }
+        2 => { codec_result("bincode decode", bincode::deserialize(payload)) }
+        _ => {
+            Err(AppCommonError::codec_msg(format!("unknown value format {}", format)))
+        }
+    }
+}
+
+pub fn decode_value<T>(raw : &[u8]) -> types::Result<T>
+    where T : DeserializeOwned
+{
+    let mut buf = raw.to_vec();
+    decode(&mut buf)
+}
+
+#[derive(Default)]
+struct RedisCodecRegistry {
+    default : RedisCodec,
+    types : HashMap<&'static str, RedisCodec>,
+}
+
+lazy_static!(
+  static ref REDIS_CODEC_REGISTRY : RwLock<RedisCodecRegistry> = RwLock::new(Default::default());
+);
+
+// codec of every type without its own, compact json unless changed
+pub fn set_default_redis_codec(codec : RedisCodec) {
+    if let Ok(mut x) = REDIS_CODEC_REGISTRY.write() {
+        x.default = codec;
+    }
+}
+
+pub fn set_redis_codec<T : ?Sized>(codec : RedisCodec) {
+    if let Ok(mut x) = REDIS_CODEC_REGISTRY.write() {
+        x.types.insert(std::any::type_name::<T>(), codec);
+    }
+}
+
+pub fn get_redis_codec<T : ?Sized>() -> RedisCodec {
+    match REDIS_CODEC_REGISTRY.read() {
+        Ok(x) => {
+            x.types.get(std::any::type_name::<T>()).copied().unwrap_or(x.default)
+        }
+        Err(_) => { Default::default() }
+    }
+}
+
+// encodes with the codec chosen for T
+pub fn encode<T>(data : &T) -> types::Result<Vec<u8>>
+    where T : Serialize + ?Sized
+{
+    get_redis_codec::<T>().encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id : u64,
+        name : String,
+        tags : Vec<String>,
+        parent : Option<u64>,
+    }
+
+    fn sample() -> Sample {
+        Sample{
+            id: 42,
+            name: "node-1".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            parent: None,
+        }
+    }
+
+    fn compressions() -> Vec<RedisCompression> {
+        vec![
+            RedisCompression::None,
+            #[cfg(feature = "zstd")]
+            RedisCompression::Zstd(3),
+            #[cfg(feature = "lz4")]
+            RedisCompression::Lz4,
+        ]
+    }
+
+    #[test]
+    fn round_trip_every_format_and_compression() {
+        let formats = [RedisFormat::PrettyJson, RedisFormat::Json, RedisFormat::MessagePack, RedisFormat::Bincode];
+        for format in formats {
+            for compression in compressions() {
+                let codec = RedisCodec::new(format, compression);
+                let raw = codec.encode(&sample()).unwrap();
+                let v : Sample = decode_value(&raw).unwrap();
+                assert_eq!(v, sample(), "{:?}", codec);
+            }
+        }
+    }
+
+    #[test]
+    fn plain_json_has_no_header() {
+        let raw = RedisCodec::default().encode(&sample()).unwrap();
+        assert_eq!(raw[0], b'{');
+    }
+
+    #[test]
+    fn decodes_legacy_pretty_json() {
+        let raw = serde_json::to_vec_pretty(&sample()).unwrap();
+        let v : Sample = decode_value(&raw).unwrap();
+        assert_eq!(v, sample());
+    }
+
+    #[test]
+    fn truncated_header_is_an_error() {
+        for raw in [vec![REDIS_CODEC_MAGIC], vec![REDIS_CODEC_MAGIC, 1], vec![REDIS_CODEC_MAGIC, 1, 0]] {
+            assert!(decode_value::<Sample>(&raw).is_err(), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn unknown_format_and_compression_are_codec_errors() {
+        let e = decode_value::<Sample>(&[REDIS_CODEC_MAGIC, 9, 0, 1]).unwrap_err();
+        assert_eq!(e.subsystem(), "codec");
+        let e = decode_value::<Sample>(&[REDIS_CODEC_MAGIC, 1, 9, 1]).unwrap_err();
+        assert_eq!(e.subsystem(), "codec");
+        assert!(!e.is_retryable());
+    }
+}
@@ -1,16 +1,14 @@
-extern crate redis_cluster_rs;
 use std::fmt::{Debug};
 use chrono::{DateTime, Duration, Local};
-use redis_cluster_rs::{Client, Commands, Connection, RedisResult};
-//use redis::AsyncCommands;
+use redis::{AsyncCommands, RedisResult, SetOptions};
+use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use crate::libs::types;
+use crate::libs::error::AppCommonError;
 use log::{error, info, debug};
-use redis::cluster::ClusterClient;
-use redis::cluster_async::ClusterConnection;
-use redis_cluster_rs::redis::{ErrorKind};
-use crate::libs::config::get_config;
-use crate::libs::json::json_impl;
+use crate::libs::config::{get_config, strip_credentials};
+use crate::libs::redis_codec;
+use crate::libs::redis_async_pool::{get_redis_async_pool, open_connection, RedisConnection};
 
 pub trait RedisKeyMaker {
     fn key(&self) -> String;
@@ -30,58 +28,71 @@ pub trait RedisKeyIndex {
     fn index(&self) -> i64;
 }
 
-pub struct RedisOp {}
+pub trait RedisHashFieldMaker {
+    fn field(&self) -> String;
+}
 
-impl RedisOp {
-    // todo : async cluster client pool use crate bb8-redis-cluster
-    pub async fn _connect_async() -> types::Result<ClusterConnection> {
-        let vec = get_config().lock().await.get_redis_config();
-        info!("redis nodes : {:?}\n", vec);
-        let init_nodes = vec.iter().map(|a| a.as_str()).collect();
-        match ClusterClient::new(init_nodes) {
-            Ok(v) => {
-                match v.get_async_connection().await {
-                    Ok(v1) => {
-                        return Ok(v1);
-                    }
-                    Err(e) => {
-                        Err(e.to_string())?
-                    }
-                }
-            }
-            Err(e) => {
-                Err(e.to_string())?
+pub trait RedisSetMemberMaker {
+    fn member(&self) -> String;
+}
+
+pub const REDIS_CLUSTER_SLOTS : u16 = 16384;
+
+fn crc16(data : &[u8]) -> u16 {
+    // CRC16/XMODEM, the checksum redis cluster hashes keys with
+    let mut crc : u16 = 0;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
             }
         }
     }
+    crc
+}
 
-    pub async fn connect() -> types::Result<Connection> {
-        let vec = get_config().lock().await.get_redis_config();
-        info!("redis nodes : {:?}\n", vec);
-        let init_nodes = vec.iter().map(|a| a.as_str()).collect();
-        match Client::open(init_nodes) {
-            Ok(v) => {
-                match v.get_connection() {
-                    Ok(v1) => {
-                        return Ok(v1);
-                    }
-                    Err(e) => {
-                        Err(e.to_string())?
-                    }
-                }
-            }
-            Err(e) => {
-                Err(e.to_string())?
+// cluster slot of a key, only the {hash tag} part is hashed when present
+pub fn key_slot(key : &str) -> u16 {
+    let bytes = key.as_bytes();
+    let mut hashed = bytes;
+    if let Some(open) = bytes.iter().position(|x| *x == b'{') {
+        if let Some(close) = bytes[open + 1..].iter().position(|x| *x == b'}') {
+            if close > 0 {
+                hashed = &bytes[open + 1..open + 1 + close];
             }
         }
     }
+    crc16(hashed) % REDIS_CLUSTER_SLOTS
+}
+
+pub struct RedisOp {}
 
-    pub async fn reconnect(c : &mut Connection, last : &mut DateTime<Local>, ty : &str) -> bool {
+impl RedisOp {
+    // shared connection of the async pool, pass it to the calls below.
+    // the pool is built from the config unless init_pool ran first
+    pub async fn pooled() -> types::Result<RedisConnection> {
+        get_redis_async_pool().get_or_init().await
+    }
+
+    // dedicated connection, not shared through the async pool. for blocking
+    // commands (br_pop, XREADGROUP BLOCK) that would stall a pooled socket
+    pub async fn connect() -> types::Result<RedisConnection> {
+        let vec = get_config().lock().await.get_redis_config();
+        let nodes : Vec<String> = vec.iter().map(|x| strip_credentials(x)).collect();
+        info!("redis nodes : {:?}\n", nodes);
+        open_connection(&vec).await
+    }
+
+    pub async fn reconnect(c : &mut RedisConnection, last : &mut DateTime<Local>, ty : &str) -> bool {
         let now = Local::now();
         if now.signed_duration_since(*last) <= Duration::seconds(1) {
             return true;
         }
-        if c.check_connection() {
+        let ping : RedisResult<String> = redis::cmd("PING").query_async(c).await;
+        if ping.is_ok() {
             return true
         }
         let f = match Self::connect().await {
@@ -98,185 +109,528 @@ impl RedisOp {
         true
     }
 
-    pub async fn get<'de, T>(redis_op : &mut Connection, stx: &'de mut String, data: &mut T) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    pub async fn get<'de, T>(redis_op : &mut RedisConnection, stx: &'de mut Vec<u8>, data: &mut T) -> types::Result<()>
         where T : Debug + Deserialize<'de> + RedisKeyMaker
     {
-        match redis_op.get(data.key()) {
-            Ok(v) => {
+        let key = data.key();
+        match redis_op.get(key.clone()).await {
+            Ok(Some(v)) => {
                 *stx = v;
             }
-            Err(x) => {
-                Err(format!("get key failed {}\n", x.to_string()))?
-            }
-        }
-        match json_impl::unmarshal(stx, data) {
-            Ok(_) => {
-                info!("get : {:?}\n", &data);
-                return Ok(())
+            Ok(None) => {
+                return Err(AppCommonError::not_found(key));
             }
-            _ => {
-                Err("get data failed\n")?
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("get#{} key failed", key), x));
             }
         }
+        *data = redis_codec::decode(stx)?;
+        info!("get : {:?}\n", &data);
         Ok(())
     }
 
-    pub async fn set<T>(redis_op : &mut Connection, data : &T) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    pub async fn set<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<()>
         where T : Debug + Serialize + RedisKeyMaker
     {
+        let key = data.key();
         info!("set : {:?}\n", &data);
-        let j = json_impl::marshal(data).unwrap();
-        let _:() = redis_op.set(data.key(), j).unwrap();
+        let j = redis_codec::encode(data)?;
+        let r : RedisResult<()> = redis_op.set(key.clone(), j).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("set#{} failed", key), x));
+        }
         Ok(())
     }
 
-    pub async fn del<T>(redis_op : &mut Connection, data : &T) -> Result<(), Box<dyn std::error::Error>>
+    pub async fn del<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<()>
         where T : Debug + RedisKeyMaker
     {
+        let key = data.key();
         info!("del : {:?}\n", &data);
-        let _:() = redis_op.del(data.key()).unwrap();
+        let r : RedisResult<()> = redis_op.del(key.clone()).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("del#{} failed", key), x));
+        }
         Ok(())
     }
 
-    pub async fn l_push<T>(redis_op: &mut Connection, data : &T) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    pub async fn l_push<T>(redis_op: &mut RedisConnection, data : &T) -> types::Result<()>
         where T : Debug + Serialize + RedisKeyMaker
     {
         let key = data.key();
         info!("l_push#{} : {:?}\n", key.clone(), &data);
-        let j = json_impl::marshal(data).unwrap();
-        let _:() = redis_op.lpush(key, j).unwrap();
+        let j = redis_codec::encode(data)?;
+        let r : RedisResult<()> = redis_op.lpush(key.clone(), j).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("l_push#{} failed", key), x));
+        }
         Ok(())
     }
 
-    pub async fn r_pop<'de, T>(redis_op : &mut redis_cluster_rs::Connection, stx: &'de mut String, data : &mut T) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>
+    pub async fn r_pop<'de, T>(redis_op : &mut RedisConnection, stx: &'de mut Vec<u8>, data : &mut T) -> types::Result<bool>
         where T : Debug + Deserialize<'de> + RedisKeyMaker
     {
-        //let mut c = redis_pool::get_redis_pool().connection().await;
         let key = data.key();
-        match redis_op.rpop(key.clone()) {
-            Ok(v) => {
+        match redis_op.rpop(key.clone(), None).await {
+            Ok(Some(v)) => {
                 *stx = v;
             }
-            Err(x) => {
-                if x.kind() != ErrorKind::TypeError {
-                    Err(format!("r_pop#{} resp failed {}\n", key, x.to_string()))?
-                }
+            Ok(None) => {
                 return Ok(false);
             }
-        }
-        match json_impl::unmarshal(stx, data) {
-            Ok(_) => {
-                info!("r_pop#{} : {:?}\n", key, &data);
-                return Ok(true);
-            }
-            _ => {
-                Err(format!("r_pop#{} unmarshal data failed\n", key))?
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("r_pop#{} resp failed", key), x));
             }
         }
-        Ok(false)
+        *data = redis_codec::decode(stx)?;
+        info!("r_pop#{} : {:?}\n", key, &data);
+        Ok(true)
     }
 
-    pub async fn br_pop<'de, T>(redis_op : &mut Connection,
+    // holds the connection for up to time_out secs, pass a dedicated one from connect()
+    pub async fn br_pop<'de, T>(redis_op : &mut RedisConnection,
                                 time_out : usize,
-                                stx: &'de mut String,
+                                stx: &'de mut Vec<u8>,
                                 data : &mut T
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    ) -> types::Result<()>
         where T : Debug + Deserialize<'de> + RedisKeyMaker
     {
         let key = data.key();
-        let result: RedisResult<Option<(String, String)>> = redis_op.brpop(key.clone(), time_out);
+        let result: RedisResult<Option<(String, Vec<u8>)>> = redis_op.brpop(key.clone(), time_out).await;
         match result {
             Ok(Some((_, element))) => {
-                debug!("br_pop #{} ok value {}\n", key, element);
+                debug!("br_pop #{} ok value {} bytes\n", key, element.len());
                 *stx = element;
             }
             Ok(None) => {
-                Err(format!("br_pop#{} key none\n", key))?
+                return Err(AppCommonError::not_found(key));
             }
             Err(x) => {
-                Err(format!("br_pop#{} key failed {}\n", key, x.to_string()))?
-            }
-        }
-        match json_impl::unmarshal(stx, data) {
-            Ok(_) => {
-                info!("br_pop#{} : {:?}\n", key, &data);
-                return Ok(())
-            }
-            _ => {
-                Err(format!("br_pop#{} data failed\n", key))?
+                return Err(AppCommonError::redis(format!("br_pop#{} key failed", key), x));
             }
         }
+        *data = redis_codec::decode(stx)?;
+        info!("br_pop#{} : {:?}\n", key, &data);
         Ok(())
     }
 
-    pub async fn l_len<T>(redis_op : &mut Connection, data : &T) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>
+    pub async fn l_len<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<i64>
         where T : Debug + RedisKeyMaker
     {
         let key = data.key();
         info!("len : key={}\n", key);
-        let l : i64 = redis_op.llen(key).unwrap();
-        Ok(l)
+        match redis_op.llen(key.clone()).await {
+            Ok(l) => {
+                Ok(l)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("l_len#{} failed", key), x))
+            }
+        }
     }
 
-    pub async fn z_add<T>(redis_op : &mut Connection, data : &T) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    pub async fn z_add<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<()>
         where T : Debug + RedisKeyMaker + RedisScoreMemberMaker
     {
         let key = data.key();
         info!("z_add : key={} member={:?}\n", key, &data);
-        let _:() = redis_op.zadd(key, data.member(), data.score()).unwrap();
+        let r : RedisResult<()> = redis_op.zadd(key.clone(), data.member(), data.score()).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("z_add#{} failed", key), x));
+        }
         Ok(())
     }
 
-    pub async fn z_rem<T>(redis_op : &mut Connection, data : &T) -> Result<(), Box<dyn std::error::Error>>
+    pub async fn z_rem<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<()>
         where T : Debug + RedisKeyMaker + RedisScoreMemberMaker
     {
         let key = data.key();
         info!("z_rem : key={} member={:?}\n", key, &data);
-        let _:() = redis_op.zrem(key, data.member()).unwrap();
+        let r : RedisResult<()> = redis_op.zrem(key.clone(), data.member()).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("z_rem#{} failed", key), x));
+        }
         Ok(())
     }
 
-    pub async fn z_range_by_score<T>(redis_op : &mut Connection,
+    pub async fn z_range_by_score<T>(redis_op : &mut RedisConnection,
                                      data : &T,
                                      min : String,
                                      max : String,
                                      offset : isize,
                                      page : isize
-    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>>
+    ) -> types::Result<Vec<String>>
         where T : Debug + Serialize + RedisKeyMaker
     {
         let key = data.key();
         debug!("z_range_by_score :  key={} min = {} max = {} filter={:?}\n", key, min, max, &data);
-        let ret = redis_op.zrangebyscore_limit(key, min, max, offset, page);
+        let ret = redis_op.zrangebyscore_limit(key.clone(), min, max, offset, page).await;
         match ret  {
             Ok(v) => {
-                return Ok(v)
+                Ok(v)
             }
             Err(x) => {
-                Err(format!("z_range_by_score key failed {}\n", x.to_string()))?
+                Err(AppCommonError::redis(format!("z_range_by_score#{} key failed", key), x))
             }
         }
-        Ok(vec![])
     }
 
-    pub async fn z_count<T>(redis_op : &mut Connection,
+    pub async fn z_count<T>(redis_op : &mut RedisConnection,
                             data : &T,
                             min : String,
                             max : String
-    ) -> Result<i32, Box<dyn std::error::Error + Send + Sync>>
+    ) -> types::Result<i32>
         where T : Debug + Serialize + RedisKeyMaker
     {
         let key = data.key();
         debug!("z_count :  key={} filter={:?}\n", key, &data);
-        let  ret = redis_op.zcount(key, min, max);
+        let  ret = redis_op.zcount(key.clone(), min, max).await;
         match ret  {
             Ok(v) => {
-                return Ok(v);
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("z_count#{} key failed", key), x))
+            }
+        }
+    }
+
+    // SET with NX/XX/EX/PX, false when the NX/XX condition kept the value from being written
+    pub async fn set_options<T>(redis_op : &mut RedisConnection, data : &T, options : SetOptions) -> types::Result<bool>
+        where T : Debug + Serialize + RedisKeyMaker
+    {
+        let key = data.key();
+        info!("set_options : {:?}\n", &data);
+        let j = redis_codec::encode(data)?;
+        let r : RedisResult<Option<String>> = redis_op.set_options(key.clone(), j, options).await;
+        match r {
+            Ok(v) => {
+                Ok(v.is_some())
             }
             Err(x) => {
-                Err(format!("z_counter key failed {}\n", x.to_string()))?
+                Err(AppCommonError::redis(format!("set_options#{} failed", key), x))
             }
         }
-        Ok(0)
     }
-}
\ No newline at end of file
+
+    pub async fn h_set<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<()>
+        where T : Debug + Serialize + RedisKeyMaker + RedisHashFieldMaker
+    {
+        let key = data.key();
+        let field = data.field();
+        info!("h_set : key={} field={} {:?}\n", key, field, &data);
+        let j = redis_codec::encode(data)?;
+        let r : RedisResult<()> = redis_op.hset(key.clone(), field.clone(), j).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("h_set#{}.{} failed", key, field), x));
+        }
+        Ok(())
+    }
+
+    pub async fn h_get<'de, T>(redis_op : &mut RedisConnection, stx: &'de mut Vec<u8>, data: &mut T) -> types::Result<()>
+        where T : Debug + Deserialize<'de> + RedisKeyMaker + RedisHashFieldMaker
+    {
+        let key = data.key();
+        let field = data.field();
+        match redis_op.hget(key.clone(), field.clone()).await {
+            Ok(Some(v)) => {
+                *stx = v;
+            }
+            Ok(None) => {
+                return Err(AppCommonError::not_found(format!("{}.{}", key, field)));
+            }
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("h_get#{}.{} failed", key, field), x));
+            }
+        }
+        *data = redis_codec::decode(stx)?;
+        info!("h_get : {:?}\n", &data);
+        Ok(())
+    }
+
+    // raw field -> value map, values are in redis_codec format, decode them with redis_codec::decode_value
+    pub async fn h_get_all<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<HashMap<String, Vec<u8>>>
+        where T : Debug + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("h_get_all : key={}\n", key);
+        match redis_op.hgetall(key.clone()).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("h_get_all#{} failed", key), x))
+            }
+        }
+    }
+
+    pub async fn h_del<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker + RedisHashFieldMaker
+    {
+        let key = data.key();
+        let field = data.field();
+        info!("h_del : key={} field={}\n", key, field);
+        let r : RedisResult<i64> = redis_op.hdel(key.clone(), field.clone()).await;
+        match r {
+            Ok(v) => {
+                Ok(v > 0)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("h_del#{}.{} failed", key, field), x))
+            }
+        }
+    }
+
+    pub async fn h_incr_by<T>(redis_op : &mut RedisConnection, data : &T, delta : i64) -> types::Result<i64>
+        where T : Debug + RedisKeyMaker + RedisHashFieldMaker
+    {
+        let key = data.key();
+        let field = data.field();
+        debug!("h_incr_by : key={} field={} delta={}\n", key, field, delta);
+        match redis_op.hincr(key.clone(), field.clone(), delta).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("h_incr_by#{}.{} failed", key, field), x))
+            }
+        }
+    }
+
+    pub async fn s_add<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker + RedisSetMemberMaker
+    {
+        let key = data.key();
+        let member = data.member();
+        info!("s_add : key={} member={:?}\n", key, &data);
+        let r : RedisResult<i64> = redis_op.sadd(key.clone(), member).await;
+        match r {
+            Ok(v) => {
+                Ok(v > 0)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("s_add#{} failed", key), x))
+            }
+        }
+    }
+
+    pub async fn s_rem<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker + RedisSetMemberMaker
+    {
+        let key = data.key();
+        let member = data.member();
+        info!("s_rem : key={} member={:?}\n", key, &data);
+        let r : RedisResult<i64> = redis_op.srem(key.clone(), member).await;
+        match r {
+            Ok(v) => {
+                Ok(v > 0)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("s_rem#{} failed", key), x))
+            }
+        }
+    }
+
+    pub async fn s_members<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<Vec<String>>
+        where T : Debug + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("s_members : key={}\n", key);
+        match redis_op.smembers(key.clone()).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("s_members#{} failed", key), x))
+            }
+        }
+    }
+
+    pub async fn s_is_member<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker + RedisSetMemberMaker
+    {
+        let key = data.key();
+        debug!("s_is_member : key={} member={:?}\n", key, &data);
+        match redis_op.sismember(key.clone(), data.member()).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("s_is_member#{} failed", key), x))
+            }
+        }
+    }
+
+    // false when the key does not exist
+    pub async fn expire<T>(redis_op : &mut RedisConnection, data : &T, secs : usize) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("expire : key={} secs={}\n", key, secs);
+        match redis_op.expire(key.clone(), secs).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("expire#{} failed", key), x))
+            }
+        }
+    }
+
+    pub async fn p_expire<T>(redis_op : &mut RedisConnection, data : &T, ms : usize) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("p_expire : key={} ms={}\n", key, ms);
+        match redis_op.pexpire(key.clone(), ms).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("p_expire#{} failed", key), x))
+            }
+        }
+    }
+
+    // remaining secs, -1 without expiry, -2 when the key does not exist
+    pub async fn ttl<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<i64>
+        where T : Debug + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("ttl : key={}\n", key);
+        match redis_op.ttl(key.clone()).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("ttl#{} failed", key), x))
+            }
+        }
+    }
+
+    pub async fn persist<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<bool>
+        where T : Debug + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("persist : key={}\n", key);
+        match redis_op.persist(key.clone()).await {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("persist#{} failed", key), x))
+            }
+        }
+    }
+
+    fn cluster_primaries(slots : &redis::Value) -> Vec<String> {
+        let mut nodes : Vec<String> = vec![];
+        let ranges = match slots {
+            redis::Value::Bulk(v) => { v }
+            _ => {
+                return nodes;
+            }
+        };
+        for r in ranges {
+            let items = match r {
+                redis::Value::Bulk(v) => { v }
+                _ => { continue }
+            };
+            // [start, end, [host, port, id], replicas...]
+            if let Some(redis::Value::Bulk(primary)) = items.get(2) {
+                if let (Some(redis::Value::Data(host)), Some(redis::Value::Int(port))) = (primary.first(), primary.get(1)) {
+                    let addr = format!("{}:{}", String::from_utf8_lossy(host), port);
+                    if !nodes.contains(&addr) {
+                        nodes.push(addr);
+                    }
+                }
+            }
+        }
+        nodes
+    }
+
+    async fn scan_node<C>(conn : &mut C, node : &str, pattern : &str, keys : &mut Vec<String>) -> types::Result<()>
+        where C : redis::aio::ConnectionLike
+    {
+        let mut cursor : u64 = 0;
+        loop {
+            let r : RedisResult<(u64, Vec<String>)> = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(conn)
+                .await;
+            let (next, batch) = match r {
+                Ok(v) => { v }
+                Err(x) => {
+                    return Err(AppCommonError::redis(format!("scan_match#{} on {}", pattern, node), x));
+                }
+            };
+            keys.extend(batch);
+            if next == 0 {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    // the cluster client can't route SCAN, so every primary is walked on its cached node
+    // connection of the async pool. a standalone or sentinel primary is scanned directly
+    pub async fn scan_match(redis_op : &mut RedisConnection, pattern : &str) -> types::Result<Vec<String>> {
+        let mut keys = vec![];
+        if !redis_op.is_cluster() {
+            Self::scan_node(redis_op, "node", pattern, &mut keys).await?;
+            keys.sort();
+            keys.dedup();
+            return Ok(keys);
+        }
+        let slots : redis::Value = match redis::cmd("CLUSTER").arg("SLOTS").query_async(redis_op).await {
+            Ok(v) => { v }
+            Err(x) => {
+                return Err(AppCommonError::redis("scan_match cluster slots", x));
+            }
+        };
+        for addr in Self::cluster_primaries(&slots) {
+            debug!("scan_match : node={} pattern={}\n", addr, pattern);
+            let pool = get_redis_async_pool();
+            let mut conn = pool.node_connection(&addr).await?;
+            if let Err(e) = Self::scan_node(&mut conn, &addr, pattern, &mut keys).await {
+                pool.forget_node_connection(&addr).await;
+                return Err(e);
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_slot_matches_redis() {
+        // CLUSTER KEYSLOT foo
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn key_slot_hashes_the_tag_only() {
+        assert_eq!(key_slot("{user1000}.following"), key_slot("{user1000}.followers"));
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot("foo{bar}{zap}"), key_slot("bar"));
+        assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+    }
+
+    #[test]
+    fn key_slot_empty_tag_hashes_the_whole_key() {
+        assert_eq!(key_slot("{}"), crc16(b"{}") % REDIS_CLUSTER_SLOTS);
+        assert_eq!(key_slot("foo{}{bar}"), crc16(b"foo{}{bar}") % REDIS_CLUSTER_SLOTS);
+        assert_ne!(key_slot("foo{}{bar}"), key_slot("bar"));
+        assert_eq!(key_slot("foo{"), crc16(b"foo{") % REDIS_CLUSTER_SLOTS);
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{error, info, warn};
+use rand::Rng;
+use redis::RedisResult;
+use tokio::task::JoinHandle;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_script::{get_redis_scripts, RedisScriptCall};
+
+const SCRIPT_LOCK_RELEASE : &str = "redis_lock_release";
+const SCRIPT_LOCK_EXTEND : &str = "redis_lock_extend";
+
+// delete / extend only while the key still holds our token
+const SCRIPT_LOCK_RELEASE_CODE : &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+const SCRIPT_LOCK_EXTEND_CODE : &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+async fn register_scripts() {
+    let scripts = get_redis_scripts();
+    if !scripts.is_registered(SCRIPT_LOCK_RELEASE).await {
+        scripts.register(SCRIPT_LOCK_RELEASE, SCRIPT_LOCK_RELEASE_CODE).await;
+    }
+    if !scripts.is_registered(SCRIPT_LOCK_EXTEND).await {
+        scripts.register(SCRIPT_LOCK_EXTEND, SCRIPT_LOCK_EXTEND_CODE).await;
+    }
+}
+
+async fn release_token(conn : &mut RedisConnection, key : &str, token : &str) -> types::Result<bool> {
+    let call = RedisScriptCall::new(SCRIPT_LOCK_RELEASE).key(key).arg(token);
+    let r : i64 = get_redis_scripts().invoke_value(conn, &call).await?;
+    Ok(r > 0)
+}
+
+async fn extend_token(conn : &mut RedisConnection, key : &str, token : &str, ttl : Duration) -> types::Result<bool> {
+    let call = RedisScriptCall::new(SCRIPT_LOCK_EXTEND).key(key).arg(token).arg(ttl.as_millis() as u64);
+    let r : i64 = get_redis_scripts().invoke_value(conn, &call).await?;
+    Ok(r > 0)
+}
+
+// lock on a single redis deployment: SET key token NX PX ttl.
+// the token is unique per acquisition so only the holder can extend or release it
+pub struct RedisLock {}
+
+impl RedisLock {
+    pub async fn try_acquire(conn : &RedisConnection, key : &str, ttl : Duration) -> types::Result<Option<RedisLockGuard>> {
+        register_scripts().await;
+        let token = format!("{}:{}", get_app_instance().get_application_uuid().await, uuid::Uuid::new_v4());
+        let mut c = conn.clone();
+        let r : RedisResult<Option<String>> = redis::cmd("SET")
+            .arg(key)
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut c)
+            .await;
+        match r {
+            Ok(Some(_)) => {
+                info!("redis lock#{} acquired, token {}\n", key, token);
+                Ok(Some(RedisLockGuard::new(c, key, token, ttl)))
+            }
+            Ok(None) => { Ok(None) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("redis lock#{} acquire", key), x))
+            }
+        }
+    }
+
+    // retry with a small random backoff until wait elapses, None when still held by someone else
+    pub async fn acquire(conn : &RedisConnection, key : &str, ttl : Duration, wait : Duration) -> types::Result<Option<RedisLockGuard>> {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            if let Some(g) = Self::try_acquire(conn, key, ttl).await? {
+                return Ok(Some(g));
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let backoff = Duration::from_millis(rand::thread_rng().gen_range(20..100));
+            tokio::time::sleep(backoff.min(deadline - now)).await;
+        }
+    }
+}
+
+// held lock, the ttl is extended in the background every ttl/3 while the guard lives.
+// dropping the guard releases the lock from a spawned task, like defer::Defer runs its closure
+pub struct RedisLockGuard {
+    conn : RedisConnection,
+    key : String,
+    token : String,
+    held : Arc<AtomicBool>,
+    keeper : Option<JoinHandle<()>>,
+}
+
+impl RedisLockGuard {
+    fn new(conn : RedisConnection, key : &str, token : String, ttl : Duration) -> Self {
+        let held = Arc::new(AtomicBool::new(true));
+        let keeper = tokio::spawn(Self::keep_extended(conn.clone(), key.to_string(), token.clone(), ttl, held.clone()));
+        Self{
+            conn,
+            key: key.to_string(),
+            token,
+            held,
+            keeper: Some(keeper),
+        }
+    }
+
+    async fn keep_extended(mut conn : RedisConnection, key : String, token : String, ttl : Duration, held : Arc<AtomicBool>) {
+        let interval = (ttl / 3).max(Duration::from_millis(10));
+        // the key is known to live until extended + ttl
+        let mut extended = tokio::time::Instant::now();
+        loop {
+            tokio::time::sleep(interval).await;
+            // taken before the call, the new expiry is at least this + ttl
+            let now = tokio::time::Instant::now();
+            match extend_token(&mut conn, &key, &token, ttl).await {
+                Ok(true) => {
+                    extended = now;
+                }
+                Ok(false) => {
+                    warn!("redis lock#{} lost, token {}\n", key, token);
+                    held.store(false, Ordering::SeqCst);
+                    return;
+                }
+                Err(e) => {
+                    // keep trying until the ttl runs out, the key may still be ours
+                    error!("redis lock#{} extend failed, err {}\n", key, e);
+                    if extended.elapsed() >= ttl {
+                        warn!("redis lock#{} lost, no extension for {:?}\n", key, ttl);
+                        held.store(false, Ordering::SeqCst);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    // false once an extension found the key expired or taken over, or none succeeded for a whole ttl
+    pub fn is_held(&self) -> bool {
+        self.held.load(Ordering::SeqCst)
+    }
+
+    pub async fn release(mut self) -> types::Result<bool> {
+        if let Some(k) = self.keeper.take() {
+            k.abort();
+        }
+        self.held.store(false, Ordering::SeqCst);
+        let r = release_token(&mut self.conn, &self.key, &self.token).await;
+        info!("redis lock#{} released, result {:?}\n", self.key, r);
+        r
+    }
+}
+
+impl Drop for RedisLockGuard {
+    fn drop(&mut self) {
+        let keeper = match self.keeper.take() {
+            Some(v) => { v }
+            None => {
+                // released explicitly
+                return;
+            }
+        };
+        keeper.abort();
+        self.held.store(false, Ordering::SeqCst);
+        let mut conn = self.conn.clone();
+        let key = self.key.clone();
+        let token = self.token.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(h) => {
+                h.spawn(async move {
+                    if let Err(e) = release_token(&mut conn, &key, &token).await {
+                        error!("redis lock#{} release on drop failed, err {}\n", key, e);
+                    }
+                });
+            }
+            Err(_) => {
+                warn!("redis lock#{} dropped outside runtime, left to expire\n", key);
+            }
+        }
+    }
+}
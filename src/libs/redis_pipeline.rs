@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use log::debug;
+use redis::{FromRedisValue, RedisResult, Value};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::task::JoinSet;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::redis_codec;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_impl::{key_slot, RedisHashFieldMaker, RedisKeyMaker, RedisScoreMemberMaker, RedisSetMemberMaker};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RedisReplyKind {
+    Unit,
+    Int,
+    Bool,
+    Data,
+}
+
+// reply of one queued command, in the order the commands were added
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedisPipelineReply {
+    Ok,
+    Int(i64),
+    Bool(bool),
+    // value read by a queued get, still encoded
+    Data(Option<Vec<u8>>),
+}
+
+impl RedisPipelineReply {
+    fn from_value(kind : RedisReplyKind, v : &Value) -> RedisResult<Self> {
+        Ok(match kind {
+            RedisReplyKind::Unit => { Self::Ok }
+            RedisReplyKind::Int => { Self::Int(i64::from_redis_value(v)?) }
+            RedisReplyKind::Bool => { Self::Bool(bool::from_redis_value(v)?) }
+            RedisReplyKind::Data => { Self::Data(Option::<Vec<u8>>::from_redis_value(v)?) }
+        })
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(v) => { Some(*v) }
+            _ => { None }
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(v) => { Some(*v) }
+            Self::Int(v) => { Some(*v > 0) }
+            _ => { None }
+        }
+    }
+
+    pub fn decode<T>(&self) -> types::Result<Option<T>>
+        where T : DeserializeOwned
+    {
+        match self {
+            Self::Data(Some(raw)) => {
+                Ok(Some(redis_codec::decode_value(raw)?))
+            }
+            _ => { Ok(None) }
+        }
+    }
+}
+
+struct RedisPipelineCommand {
+    key : String,
+    cmd : redis::Cmd,
+    kind : RedisReplyKind,
+}
+
+// typed batch of commands, execute() sends one pipeline per cluster slot,
+// transaction() wraps them in MULTI/EXEC and needs every key in one slot ({hash tag})
+#[derive(Default)]
+pub struct RedisPipeline {
+    commands : Vec<RedisPipelineCommand>,
+}
+
+impl RedisPipeline {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    fn push(&mut self, key : String, cmd : redis::Cmd, kind : RedisReplyKind) -> &mut Self {
+        self.commands.push(RedisPipelineCommand{
+            key,
+            cmd,
+            kind,
+        });
+        self
+    }
+
+    pub fn set<T>(&mut self, data : &T) -> types::Result<&mut Self>
+        where T : Debug + Serialize + RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(&key).arg(redis_codec::encode(data)?);
+        Ok(self.push(key, cmd, RedisReplyKind::Unit))
+    }
+
+    pub fn set_ex<T>(&mut self, data : &T, secs : usize) -> types::Result<&mut Self>
+        where T : Debug + Serialize + RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(&key).arg(redis_codec::encode(data)?).arg("EX").arg(secs);
+        Ok(self.push(key, cmd, RedisReplyKind::Unit))
+    }
+
+    pub fn get<T>(&mut self, data : &T) -> &mut Self
+        where T : RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("GET");
+        cmd.arg(&key);
+        self.push(key, cmd, RedisReplyKind::Data)
+    }
+
+    pub fn del<T>(&mut self, data : &T) -> &mut Self
+        where T : RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("DEL");
+        cmd.arg(&key);
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    pub fn l_push<T>(&mut self, data : &T) -> types::Result<&mut Self>
+        where T : Debug + Serialize + RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("LPUSH");
+        cmd.arg(&key).arg(redis_codec::encode(data)?);
+        Ok(self.push(key, cmd, RedisReplyKind::Int))
+    }
+
+    pub fn z_add<T>(&mut self, data : &T) -> &mut Self
+        where T : RedisKeyMaker + RedisScoreMemberMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("ZADD");
+        cmd.arg(&key).arg(data.score()).arg(data.member());
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    pub fn z_rem<T>(&mut self, data : &T) -> &mut Self
+        where T : RedisKeyMaker + RedisScoreMemberMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("ZREM");
+        cmd.arg(&key).arg(data.member());
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    pub fn h_set<T>(&mut self, data : &T) -> types::Result<&mut Self>
+        where T : Debug + Serialize + RedisKeyMaker + RedisHashFieldMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("HSET");
+        cmd.arg(&key).arg(data.field()).arg(redis_codec::encode(data)?);
+        Ok(self.push(key, cmd, RedisReplyKind::Int))
+    }
+
+    pub fn h_incr_by<T>(&mut self, data : &T, delta : i64) -> &mut Self
+        where T : RedisKeyMaker + RedisHashFieldMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("HINCRBY");
+        cmd.arg(&key).arg(data.field()).arg(delta);
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    pub fn s_add<T>(&mut self, data : &T) -> &mut Self
+        where T : RedisKeyMaker + RedisSetMemberMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("SADD");
+        cmd.arg(&key).arg(data.member());
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    pub fn s_rem<T>(&mut self, data : &T) -> &mut Self
+        where T : RedisKeyMaker + RedisSetMemberMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("SREM");
+        cmd.arg(&key).arg(data.member());
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    pub fn expire<T>(&mut self, data : &T, secs : usize) -> &mut Self
+        where T : RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("EXPIRE");
+        cmd.arg(&key).arg(secs);
+        self.push(key, cmd, RedisReplyKind::Bool)
+    }
+
+    pub fn p_expire<T>(&mut self, data : &T, ms : usize) -> &mut Self
+        where T : RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("PEXPIRE");
+        cmd.arg(&key).arg(ms);
+        self.push(key, cmd, RedisReplyKind::Bool)
+    }
+
+    pub fn incr_by<T>(&mut self, data : &T, delta : i64) -> &mut Self
+        where T : RedisKeyMaker
+    {
+        let key = data.key();
+        let mut cmd = redis::cmd("INCRBY");
+        cmd.arg(&key).arg(delta);
+        self.push(key, cmd, RedisReplyKind::Int)
+    }
+
+    fn build(&self, idx : &[usize], atomic : bool) -> (redis::Pipeline, Vec<RedisReplyKind>) {
+        let mut pipe = redis::pipe();
+        if atomic {
+            pipe.atomic();
+        }
+        let mut kinds = vec![];
+        for i in idx {
+            pipe.add_command(self.commands[*i].cmd.clone());
+            kinds.push(self.commands[*i].kind);
+        }
+        (pipe, kinds)
+    }
+
+    fn convert(kinds : &[RedisReplyKind], values : &[Value]) -> types::Result<Vec<RedisPipelineReply>> {
+        if kinds.len() != values.len() {
+            return Err(AppCommonError::redis_msg(format!("pipeline expect {} replies, got {}", kinds.len(), values.len())));
+        }
+        let mut out = vec![];
+        for (k, v) in kinds.iter().zip(values) {
+            match RedisPipelineReply::from_value(*k, v) {
+                Ok(r) => { out.push(r) }
+                Err(x) => {
+                    return Err(AppCommonError::redis("pipeline reply", x));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // one round trip per slot, the slot batches run concurrently
+    pub async fn execute(&self, redis_op : &mut RedisConnection) -> types::Result<Vec<RedisPipelineReply>> {
+        let mut groups : BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+        for (i, c) in self.commands.iter().enumerate() {
+            groups.entry(key_slot(&c.key)).or_default().push(i);
+        }
+        let mut tasks = JoinSet::new();
+        for (slot, idx) in groups {
+            let (pipe, kinds) = self.build(&idx, false);
+            let mut conn = redis_op.clone();
+            debug!("pipeline execute : slot={} commands={}\n", slot, idx.len());
+            tasks.spawn(async move {
+                let r : RedisResult<Vec<Value>> = pipe.query_async(&mut conn).await;
+                (slot, idx, kinds, r)
+            });
+        }
+        let mut replies : Vec<Option<RedisPipelineReply>> = self.commands.iter().map(|_| None).collect();
+        while let Some(joined) = tasks.join_next().await {
+            let (slot, idx, kinds, r) = match joined {
+                Ok(v) => { v }
+                Err(e) => {
+                    return Err(AppCommonError::redis("pipeline task", e));
+                }
+            };
+            let values = match r {
+                Ok(v) => { v }
+                Err(x) => {
+                    return Err(AppCommonError::redis(format!("pipeline slot {}", slot), x));
+                }
+            };
+            for (i, reply) in idx.into_iter().zip(Self::convert(&kinds, &values)?) {
+                replies[i] = Some(reply);
+            }
+        }
+        Ok(replies.into_iter().flatten().collect())
+    }
+
+    // MULTI/EXEC, every key must hash to the same slot
+    pub async fn transaction(&self, redis_op : &mut RedisConnection) -> types::Result<Vec<RedisPipelineReply>> {
+        if let Some(first) = self.commands.first() {
+            let slot = key_slot(&first.key);
+            if let Some(c) = self.commands.iter().find(|c| key_slot(&c.key) != slot) {
+                return Err(AppCommonError::redis_msg(format!("transaction keys {} and {} hash to different slots, use a common {{hash tag}}", first.key, c.key)));
+            }
+        } else {
+            return Ok(vec![]);
+        }
+        let idx : Vec<usize> = (0..self.commands.len()).collect();
+        let (pipe, kinds) = self.build(&idx, true);
+        let r : RedisResult<Vec<Value>> = pipe.query_async(redis_op).await;
+        match r {
+            Ok(values) => {
+                Self::convert(&kinds, &values)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis("transaction", x))
+            }
+        }
+    }
+}
@@ -1,66 +1,388 @@
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
+use log::{info, warn};
 use tokio::sync::Mutex;
-use r2d2_redis_cluster::r2d2::{Pool, PooledConnection};
+use r2d2_redis_cluster::r2d2::{ManageConnection, Pool, PooledConnection};
+use r2d2_redis_cluster::redis_cluster_rs;
+use r2d2_redis_cluster::redis_cluster_rs::redis as redis_sync;
 use r2d2_redis_cluster::RedisClusterConnectionManager;
 use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::config::{get_config, strip_credentials, Redis, RedisPoolConfig, RedisTopology};
 
-fn build_pool(uri : Vec<String>, pool_size : u32) -> types::Result<Pool<RedisClusterConnectionManager>>{
-    let redis_uri = uri
-        .iter()
-        .map(|x| x.as_str())
-        .collect::<Vec<&str>>();
-    let manager = match RedisClusterConnectionManager::new(redis_uri) {
-        Ok(v) => { v }
-        Err(e) => {
-            return Err(e.to_string())?;
+fn millis(v : Option<u64>) -> Option<Duration> {
+    v.map(Duration::from_millis)
+}
+
+// pooled connection, Commands work on it for every topology
+pub enum RedisSyncConnection {
+    Cluster(redis_cluster_rs::Connection),
+    Node(redis_sync::Connection),
+}
+
+impl redis_sync::ConnectionLike for RedisSyncConnection {
+    fn req_packed_command(&mut self, cmd : &[u8]) -> redis_sync::RedisResult<redis_sync::Value> {
+        match self {
+            Self::Cluster(c) => { c.req_packed_command(cmd) }
+            Self::Node(c) => { c.req_packed_command(cmd) }
+        }
+    }
+
+    fn req_packed_commands(&mut self, cmd : &[u8], offset : usize, count : usize) -> redis_sync::RedisResult<Vec<redis_sync::Value>> {
+        match self {
+            Self::Cluster(c) => { c.req_packed_commands(cmd, offset, count) }
+            Self::Node(c) => { c.req_packed_commands(cmd, offset, count) }
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Cluster(c) => { c.get_db() }
+            Self::Node(c) => { c.get_db() }
+        }
+    }
+}
+
+pub enum RedisSyncManager {
+    Cluster(RedisClusterConnectionManager),
+    // standalone nodes tried in order, or the sentinels of master
+    Node {
+        nodes : Vec<String>,
+        master : Option<String>,
+        // first [[redis]] entry, gives the sentinel-resolved primary its credentials and db
+        template : Box<Redis>,
+        // ACL user and password, the uris carry no credentials then
+        acl : Option<(String, String)>,
+        read_timeout : Option<Duration>,
+        write_timeout : Option<Duration>,
+    },
+}
+
+impl RedisSyncManager {
+    fn io_error(msg : &'static str) -> redis_sync::RedisError {
+        redis_sync::RedisError::from((redis_sync::ErrorKind::IoError, msg))
+    }
+
+    fn node_connect(uri : &str, acl : &Option<(String, String)>, read_timeout : Option<Duration>, write_timeout : Option<Duration>) -> redis_sync::RedisResult<redis_sync::Connection> {
+        let mut conn = redis_sync::Client::open(uri)?.get_connection()?;
+        if let Some((user, password)) = acl {
+            redis_sync::cmd("AUTH").arg(user.as_str()).arg(password.as_str()).query::<()>(&mut conn)?;
+        }
+        conn.set_read_timeout(read_timeout)?;
+        conn.set_write_timeout(write_timeout)?;
+        Ok(conn)
+    }
+
+    // asks the sentinels in order, a new connection always lands on the current primary
+    fn sentinel_primary(sentinels : &[String], master : &str) -> redis_sync::RedisResult<String> {
+        for s in sentinels {
+            let mut conn = match redis_sync::Client::open(s.as_str()).and_then(|c| c.get_connection()) {
+                Ok(v) => { v }
+                Err(e) => {
+                    warn!("sentinel {} unreachable, err {}\n", s, e);
+                    continue;
+                }
+            };
+            let r : redis_sync::RedisResult<Option<(String, u16)>> = redis_sync::cmd("SENTINEL")
+                .arg("get-master-addr-by-name")
+                .arg(master)
+                .query(&mut conn);
+            match r {
+                Ok(Some((host, port))) => {
+                    return Ok(format!("{}:{}", host, port));
+                }
+                Ok(None) => {
+                    warn!("sentinel {} doesn't know master {}\n", s, master);
+                }
+                Err(e) => {
+                    warn!("sentinel {} resolve {} failed, err {}\n", s, master, e);
+                }
+            }
+        }
+        Err(Self::io_error("no sentinel resolved the primary"))
+    }
+}
+
+impl ManageConnection for RedisSyncManager {
+    type Connection = RedisSyncConnection;
+    type Error = redis_sync::RedisError;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        match self {
+            Self::Cluster(m) => {
+                Ok(RedisSyncConnection::Cluster(m.connect()?))
+            }
+            Self::Node { nodes, master, template, acl, read_timeout, write_timeout } => {
+                if let Some(master) = master {
+                    let addr = Self::sentinel_primary(nodes, master)?;
+                    let mut uri = template.uri_for(&addr);
+                    if acl.is_some() {
+                        uri = strip_credentials(&uri);
+                    }
+                    return Ok(RedisSyncConnection::Node(Self::node_connect(&uri, acl, *read_timeout, *write_timeout)?));
+                }
+                let mut last = Self::io_error("no redis nodes configured");
+                for uri in nodes {
+                    match Self::node_connect(uri, acl, *read_timeout, *write_timeout) {
+                        Ok(v) => {
+                            return Ok(RedisSyncConnection::Node(v));
+                        }
+                        Err(e) => { last = e }
+                    }
+                }
+                Err(last)
+            }
+        }
+    }
+
+    // PING, and behind sentinels also ROLE, so a demoted primary is dropped after a failover
+    fn is_valid(&self, conn : &mut Self::Connection) -> Result<(), Self::Error> {
+        match (self, conn) {
+            (Self::Cluster(m), RedisSyncConnection::Cluster(c)) => { m.is_valid(c) }
+            (Self::Node { master, .. }, RedisSyncConnection::Node(c)) => {
+                redis_sync::cmd("PING").query::<String>(c)?;
+                if master.is_some() {
+                    let role : Vec<redis_sync::Value> = redis_sync::cmd("ROLE").query(c)?;
+                    match role.first() {
+                        Some(redis_sync::Value::Data(r)) if r.as_slice() == b"master" => {}
+                        _ => {
+                            return Err(Self::io_error("connected node is no longer the primary"));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            _ => { Err(Self::io_error("connection of another topology")) }
+        }
+    }
+
+    fn has_broken(&self, conn : &mut Self::Connection) -> bool {
+        match conn {
+            RedisSyncConnection::Cluster(_) => { false }
+            RedisSyncConnection::Node(c) => { !c.is_open() }
+        }
+    }
+}
+
+fn build_manager(uri : &[String], config : &RedisPoolConfig, topology : RedisTopology, master : String, entries : &[Redis]) -> types::Result<RedisSyncManager> {
+    // the sync client is redis 0.11, it knows neither rediss:// nor ACL users
+    if entries.iter().any(|x| x.tls) {
+        return Err(AppCommonError::config_msg("the sync redis pool has no tls support, use the async pool"));
+    }
+    let template = entries.first().cloned().unwrap_or_default();
+    let acl = match (&template.username, &template.password) {
+        (Some(u), Some(p)) => { Some((u.clone(), p.clone())) }
+        _ => { None }
+    };
+    let manager = match topology {
+        RedisTopology::Cluster => {
+            if acl.is_some() {
+                return Err(AppCommonError::config_msg("the sync redis pool can't authenticate an ACL user against a cluster"));
+            }
+            let redis_uri = uri
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<&str>>();
+            let mut manager = match RedisClusterConnectionManager::new(redis_uri) {
+                Ok(v) => { v }
+                Err(e) => {
+                    return Err(AppCommonError::redis("pool connection manager", e));
+                }
+            };
+            if let Some(p) = &template.password {
+                manager.set_password(p.clone());
+            }
+            manager.set_read_timeout(millis(config.read_timeout_ms));
+            manager.set_write_timeout(millis(config.write_timeout_ms));
+            RedisSyncManager::Cluster(manager)
+        }
+        RedisTopology::Standalone | RedisTopology::Sentinel => {
+            if topology == RedisTopology::Sentinel && master.is_empty() {
+                return Err(AppCommonError::config_msg("redis_sentinel_master is required for the sentinel topology"));
+            }
+            // redis 0.11 sends AUTH with the password alone, an ACL user is authenticated by hand
+            let nodes = if acl.is_some() {
+                uri.iter().map(|x| strip_credentials(x)).collect()
+            } else {
+                uri.to_vec()
+            };
+            RedisSyncManager::Node{
+                nodes,
+                master: if topology == RedisTopology::Sentinel { Some(master) } else { None },
+                template: Box::new(template),
+                acl,
+                read_timeout: millis(config.read_timeout_ms),
+                write_timeout: millis(config.write_timeout_ms),
+            }
         }
     };
-    let pool = match Pool::builder().max_size(pool_size)
-        .connection_timeout(Duration::from_secs(15))
+    Ok(manager)
+}
+
+fn build_pool(uri : &[String], config : &RedisPoolConfig, topology : RedisTopology, master : String, entries : &[Redis]) -> types::Result<Pool<RedisSyncManager>>{
+    let manager = build_manager(uri, config, topology, master, entries)?;
+    let pool = match Pool::builder().max_size(config.max_size)
+        .min_idle(config.min_idle.map(|x| x.min(config.max_size)))
+        .connection_timeout(Duration::from_millis(config.connection_timeout_ms))
+        .idle_timeout(millis(config.idle_timeout_ms))
+        .max_lifetime(millis(config.max_lifetime_ms))
+        .test_on_check_out(config.test_on_check_out)
         .build(manager) {
         Ok(v) => {v }
         Err(e) => {
-            return Err(e.to_string())?;
+            return Err(AppCommonError::redis("build pool", e));
         }
     };
     Ok(pool)
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct RedisPoolStats {
+    pub max_size : u32,
+    pub connections : u32,
+    pub idle : u32,
+    pub in_use : u32,
+    // checkouts since init, failed ones included
+    pub checkouts : u64,
+    pub failures : u64,
+    pub avg_wait : Duration,
+    pub max_wait : Duration,
+}
+
+#[derive(Default)]
+struct RedisPoolCounters {
+    checkouts : AtomicU64,
+    failures : AtomicU64,
+    wait_total_us : AtomicU64,
+    wait_max_us : AtomicU64,
+}
+
+impl RedisPoolCounters {
+    fn record(&self, wait : Duration, ok : bool) {
+        let us = wait.as_micros() as u64;
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wait_total_us.fetch_add(us, Ordering::Relaxed);
+        self.wait_max_us.fetch_max(us, Ordering::Relaxed);
+    }
+}
+
+struct RedisPoolInner {
+    pool : Pool<RedisSyncManager>,
+    uri : Vec<String>,
+    config : RedisPoolConfig,
+}
+
 pub struct RedisPool {
-    pool : Mutex<Option<Pool<RedisClusterConnectionManager>>>,
+    inner : Mutex<Option<RedisPoolInner>>,
+    counters : RedisPoolCounters,
 }
 
 impl RedisPool {
     const MAX_REDIS_POOL : u32 = 128;
     pub fn new() -> Self{
         Self{
-            pool: Default::default(),
+            inner: Default::default(),
+            counters: Default::default(),
         }
     }
-    pub async fn init_pool(&self, uri : Vec<String>, pool_size : u32) -> types::Result<()> {
-        let mut size = pool_size;
-        if size <= 0 {
-            size = 1;
-        }
-        if size > Self::MAX_REDIS_POOL {
-            size = Self::MAX_REDIS_POOL
+
+    // settings of the [redis_pool] config section, Some(pool_size) overrides its max_size
+    pub async fn init_pool(&self, uri : Vec<String>, pool_size : Option<u32>) -> types::Result<()> {
+        let mut config = get_config().lock().await.get_redis_pool_config();
+        if let Some(v) = pool_size {
+            config.max_size = v;
         }
-        let x = build_pool(uri, size)?;
-        let mut inner = self.pool.lock().await;
-        *inner = Some(x);
+        self.init_pool_with_config(uri, config).await
+    }
+
+    pub async fn init_pool_with_config(&self, uri : Vec<String>, mut config : RedisPoolConfig) -> types::Result<()> {
+        config.max_size = config.max_size.clamp(1, Self::MAX_REDIS_POOL);
+        let (topology, master, entries) = {
+            let c = get_config().lock().await;
+            (c.get_redis_topology(), c.get_redis_sentinel_master(), c.get_redis_nodes())
+        };
+        let pool = build_pool(&uri, &config, topology, master, &entries)?;
+        let nodes : Vec<String> = uri.iter().map(|x| strip_credentials(x)).collect();
+        info!("redis pool built, nodes {:?} config {:?}\n", nodes, config);
+        let mut inner = self.inner.lock().await;
+        *inner = Some(RedisPoolInner{
+            pool,
+            uri,
+            config,
+        });
         Ok(())
     }
-    pub async fn get(&self) -> types::Result<PooledConnection<RedisClusterConnectionManager>> {
-        let inner = self.pool.lock().await;
-        let conn = match inner.clone().unwrap().get() {
+
+    // swaps in a pool for new node addresses with the current settings.
+    // connections checked out of the old pool stay usable until they are returned
+    pub async fn rebuild(&self, uri : Vec<String>) -> types::Result<()> {
+        let config = match self.inner.lock().await.as_ref() {
+            Some(v) => { v.config.clone() }
+            None => {
+                return Err(AppCommonError::redis_msg("redis pool not initialized"));
+            }
+        };
+        self.init_pool_with_config(uri, config).await
+    }
+
+    pub async fn nodes(&self) -> Vec<String> {
+        match self.inner.lock().await.as_ref() {
+            Some(v) => { v.uri.clone() }
+            None => { vec![] }
+        }
+    }
+
+    async fn pool(&self) -> types::Result<Pool<RedisSyncManager>> {
+        match self.inner.lock().await.as_ref() {
+            Some(v) => { Ok(v.pool.clone()) }
+            None => {
+                Err(AppCommonError::redis_msg("redis pool not initialized"))
+            }
+        }
+    }
+
+    // r2d2 blocks while the pool is exhausted or a connection is opened,
+    // so the checkout runs on the blocking thread pool instead of a runtime worker
+    pub async fn get(&self) -> types::Result<PooledConnection<RedisSyncManager>> {
+        let pool = self.pool().await?;
+        let start = Instant::now();
+        let r = match tokio::task::spawn_blocking(move || pool.get()).await {
+            Ok(v) => { v }
+            Err(e) => {
+                self.counters.record(start.elapsed(), false);
+                return Err(AppCommonError::redis("pool get connection", e));
+            }
+        };
+        self.counters.record(start.elapsed(), r.is_ok());
+        let conn = match r {
             Ok(v) => { v }
             Err(e) => {
-                return Err(e.to_string())?;
+                return Err(AppCommonError::redis("pool get connection", e));
             }
         };
         Ok(conn)
     }
+
+    pub async fn stats(&self) -> types::Result<RedisPoolStats> {
+        let pool = self.pool().await?;
+        let state = pool.state();
+        let checkouts = self.counters.checkouts.load(Ordering::Relaxed);
+        let wait_total = self.counters.wait_total_us.load(Ordering::Relaxed);
+        Ok(RedisPoolStats{
+            max_size: pool.max_size(),
+            connections: state.connections,
+            idle: state.idle_connections,
+            in_use: state.connections - state.idle_connections,
+            checkouts,
+            failures: self.counters.failures.load(Ordering::Relaxed),
+            avg_wait: Duration::from_micros(wait_total.checked_div(checkouts).unwrap_or(0)),
+            max_wait: Duration::from_micros(self.counters.wait_max_us.load(Ordering::Relaxed)),
+        })
+    }
 }
 
 lazy_static!(
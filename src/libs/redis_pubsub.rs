@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use redis::{AsyncCommands, Msg, RedisResult};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::task::JoinHandle;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::config::get_config;
+use crate::libs::redis_async_pool::{node_client, RedisConnection};
+use crate::libs::redis_codec;
+use crate::libs::register::node_register_impl::get_register;
+
+// payload is ignored, every instance refreshes its node list from lookup
+pub const PUBSUB_CHANNEL_NODES_INVALIDATE : &str = "appcommon:nodes:invalidate";
+
+type RedisPubSubHandler = Arc<dyn Fn(String, Vec<u8>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+// PUBLISH is forwarded to every node of a cluster, returns receivers on the node it reached
+pub async fn publish<T>(redis_op : &mut RedisConnection, channel : &str, data : &T) -> types::Result<i64>
+    where T : Debug + Serialize
+{
+    debug!("publish#{} : {:?}\n", channel, data);
+    let j = redis_codec::encode(data)?;
+    match redis_op.publish(channel, j).await {
+        Ok(v) => { Ok(v) }
+        Err(x) => {
+            Err(AppCommonError::redis(format!("publish#{} failed", channel), x))
+        }
+    }
+}
+
+pub async fn publish_nodes_invalidation(redis_op : &mut RedisConnection) -> types::Result<i64> {
+    let uuid = get_app_instance().get_application_uuid().await;
+    publish(redis_op, PUBSUB_CHANNEL_NODES_INVALIDATE, &uuid).await
+}
+
+// channel and pattern subscriptions with typed handlers.
+// the cluster client has no pub/sub, the subscriber talks to one node at a time
+// (cluster publishes reach every node) and moves to the next configured node when
+// the connection drops, e.g. on failover. with sentinels it follows the current primary
+#[derive(Default)]
+pub struct RedisSubscriber {
+    channels : HashMap<String, RedisPubSubHandler>,
+    patterns : HashMap<String, RedisPubSubHandler>,
+}
+
+impl RedisSubscriber {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn wrap<T, F, Fut>(handler : F) -> RedisPubSubHandler
+        where T : DeserializeOwned + Send + 'static,
+              F : Fn(String, T) -> Fut + Send + Sync + 'static,
+              Fut : Future<Output = ()> + Send + 'static
+    {
+        let handler = Arc::new(handler);
+        Arc::new(move |channel : String, payload : Vec<u8>| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                match redis_codec::decode_value::<T>(&payload) {
+                    Ok(v) => {
+                        handler(channel, v).await;
+                    }
+                    Err(e) => {
+                        error!("pubsub#{} drop undecodable message, err {}\n", channel, e);
+                    }
+                }
+            }) as Pin<Box<dyn Future<Output = ()> + Send>>
+        })
+    }
+
+    pub fn on<T, F, Fut>(mut self, channel : &str, handler : F) -> Self
+        where T : DeserializeOwned + Send + 'static,
+              F : Fn(String, T) -> Fut + Send + Sync + 'static,
+              Fut : Future<Output = ()> + Send + 'static
+    {
+        self.channels.insert(channel.to_string(), Self::wrap(handler));
+        self
+    }
+
+    // glob pattern as PSUBSCRIBE, the handler gets the concrete channel name
+    pub fn on_pattern<T, F, Fut>(mut self, pattern : &str, handler : F) -> Self
+        where T : DeserializeOwned + Send + 'static,
+              F : Fn(String, T) -> Fut + Send + Sync + 'static,
+              Fut : Future<Output = ()> + Send + 'static
+    {
+        self.patterns.insert(pattern.to_string(), Self::wrap(handler));
+        self
+    }
+
+    // refresh register nodes when another instance publishes an invalidation
+    pub fn with_nodes_invalidation(self) -> Self {
+        self.on(PUBSUB_CHANNEL_NODES_INVALIDATE, |_ : String, from : String| async move {
+            info!("nodes invalidated by {}, refresh register nodes\n", from);
+            get_register().notify_update_nodes().await;
+        })
+    }
+
+    fn dispatch(&self, msg : &Msg) {
+        let channel = msg.get_channel_name().to_string();
+        let handler = if msg.from_pattern() {
+            let pattern : RedisResult<String> = msg.get_pattern();
+            match pattern {
+                Ok(p) => { self.patterns.get(&p) }
+                Err(_) => { None }
+            }
+        } else {
+            self.channels.get(&channel)
+        };
+        let handler = match handler {
+            Some(v) => { v.clone() }
+            None => {
+                return;
+            }
+        };
+        let payload : Vec<u8> = match msg.get_payload() {
+            Ok(v) => { v }
+            Err(e) => {
+                error!("pubsub#{} read payload failed, err {}\n", channel, e);
+                return;
+            }
+        };
+        tokio::spawn(handler(channel, payload));
+    }
+
+    async fn listen(&self, client : redis::Client) -> types::Result<()> {
+        let uri = client.get_connection_info().addr.to_string();
+        let conn = match client.get_async_connection().await {
+            Ok(v) => { v }
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("pubsub connect {}", uri), x));
+            }
+        };
+        let mut pubsub = conn.into_pubsub();
+        for c in self.channels.keys() {
+            if let Err(x) = pubsub.subscribe(c).await {
+                return Err(AppCommonError::redis(format!("pubsub subscribe {}", c), x));
+            }
+        }
+        for p in self.patterns.keys() {
+            if let Err(x) = pubsub.psubscribe(p).await {
+                return Err(AppCommonError::redis(format!("pubsub psubscribe {}", p), x));
+            }
+        }
+        info!("pubsub listening on {}, channels {:?} patterns {:?}\n", uri, self.channels.keys(), self.patterns.keys());
+        let mut stream = pubsub.on_message();
+        loop {
+            if get_app_instance().is_exiting().await {
+                return Ok(());
+            }
+            match tokio::time::timeout(Duration::from_secs(1), stream.next()).await {
+                Ok(Some(msg)) => {
+                    self.dispatch(&msg);
+                }
+                Ok(None) => {
+                    return Err(AppCommonError::redis_msg(format!("pubsub connection to {} closed", uri)));
+                }
+                Err(_) => {
+                    // idle, check exit status again
+                }
+            }
+        }
+    }
+
+    // runs until the app exits, handlers run on their own tasks
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut idx = 0;
+            loop {
+                if get_app_instance().is_exiting().await {
+                    info!("system exit status, stop pubsub subscriber\n");
+                    break;
+                }
+                let nodes = get_config().lock().await.get_redis_config();
+                if nodes.is_empty() {
+                    error!("pubsub subscriber without redis nodes configured\n");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                let r = match node_client(&nodes, idx).await {
+                    Ok(client) => { self.listen(client).await }
+                    Err(e) => { Err(e) }
+                };
+                idx += 1;
+                if let Err(e) = r {
+                    warn!("pubsub subscriber lost connection, reconnecting, err {}\n", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        })
+    }
+}
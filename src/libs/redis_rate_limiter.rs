@@ -0,0 +1,235 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::Utc;
+use log::{debug, error};
+use warp::{Filter, Rejection, Reply};
+use warp::http::StatusCode;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_script::{get_redis_scripts, RedisScriptCall};
+
+const SCRIPT_RATE_FIXED_WINDOW : &str = "rate_limit_fixed_window";
+const SCRIPT_RATE_SLIDING_LOG : &str = "rate_limit_sliding_log";
+const SCRIPT_RATE_TOKEN_BUCKET : &str = "rate_limit_token_bucket";
+
+// every script returns {allowed, remaining, retry_after_ms}
+
+// KEYS : counter  ARGV : limit, window ms
+const SCRIPT_RATE_FIXED_WINDOW_CODE : &str = r#"
+local n = redis.call("INCR", KEYS[1])
+if n == 1 then
+    redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+local limit = tonumber(ARGV[1])
+if n <= limit then
+    return {1, limit - n, 0}
+end
+local ttl = redis.call("PTTL", KEYS[1])
+if ttl < 0 then
+    ttl = tonumber(ARGV[2])
+end
+return {0, 0, ttl}
+"#;
+
+// KEYS : log zset  ARGV : limit, window ms, now ms, unique member
+const SCRIPT_RATE_SLIDING_LOG_CODE : &str = r#"
+local limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", now - window)
+local n = redis.call("ZCARD", KEYS[1])
+if n < limit then
+    redis.call("ZADD", KEYS[1], now, ARGV[4])
+    redis.call("PEXPIRE", KEYS[1], window)
+    return {1, limit - n - 1, 0}
+end
+local oldest = redis.call("ZRANGE", KEYS[1], 0, 0, "WITHSCORES")
+local retry = window
+if oldest[2] then
+    retry = tonumber(oldest[2]) + window - now
+end
+return {0, 0, retry}
+"#;
+
+// KEYS : bucket hash  ARGV : capacity, refill per sec, now ms
+const SCRIPT_RATE_TOKEN_BUCKET_CODE : &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call("HMGET", KEYS[1], "tokens", "ts")
+local tokens = tonumber(state[1])
+local ts = tonumber(state[2])
+if tokens == nil then
+    tokens = capacity
+    ts = now
+end
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate / 1000)
+local allowed = 0
+local retry = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+else
+    retry = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call("HSET", KEYS[1], "tokens", tostring(tokens), "ts", now)
+redis.call("PEXPIRE", KEYS[1], math.ceil(capacity * 1000 / rate) + 1000)
+return {allowed, math.floor(tokens), retry}
+"#;
+
+#[derive(Debug, Clone)]
+pub enum RateLimitAlgorithm {
+    // at most limit requests per aligned window
+    FixedWindow { limit : u64, window : Duration },
+    // at most limit requests in any window ending now
+    SlidingLog { limit : u64, window : Duration },
+    // bursts up to capacity, refilled continuously
+    TokenBucket { capacity : u64, refill_per_sec : f64 },
+}
+
+impl RateLimitAlgorithm {
+    fn check(&self) -> types::Result<()> {
+        match self {
+            Self::FixedWindow { window, .. } | Self::SlidingLog { window, .. } => {
+                if window.as_millis() == 0 {
+                    return Err(AppCommonError::config_msg(format!("rate limit {:?} needs a window of at least 1ms", self)));
+                }
+            }
+            Self::TokenBucket { refill_per_sec, .. } => {
+                if !refill_per_sec.is_finite() || *refill_per_sec <= 0.0 {
+                    return Err(AppCommonError::config_msg(format!("rate limit {:?} needs a positive refill rate", self)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed : bool,
+    pub remaining : u64,
+    pub retry_after : Duration,
+}
+
+// limits keyed per client and route, each check is one atomic script run on the key's node
+pub struct RedisRateLimiter {
+    conn : RedisConnection,
+    prefix : String,
+    algorithm : RateLimitAlgorithm,
+}
+
+impl RedisRateLimiter {
+    // rejects settings the scripts can't work with, e.g. a bucket that never refills
+    pub async fn new(conn : RedisConnection, prefix : &str, algorithm : RateLimitAlgorithm) -> types::Result<Self> {
+        algorithm.check()?;
+        let scripts = get_redis_scripts();
+        for (name, code) in [(SCRIPT_RATE_FIXED_WINDOW, SCRIPT_RATE_FIXED_WINDOW_CODE),
+                             (SCRIPT_RATE_SLIDING_LOG, SCRIPT_RATE_SLIDING_LOG_CODE),
+                             (SCRIPT_RATE_TOKEN_BUCKET, SCRIPT_RATE_TOKEN_BUCKET_CODE)] {
+            if !scripts.is_registered(name).await {
+                scripts.register(name, code).await;
+            }
+        }
+        Ok(Self{
+            conn,
+            prefix: prefix.to_string(),
+            algorithm,
+        })
+    }
+
+    pub async fn check(&self, client : &str, route : &str) -> types::Result<RateLimitDecision> {
+        let now = Utc::now().timestamp_millis();
+        let call = match &self.algorithm {
+            RateLimitAlgorithm::FixedWindow { limit, window } => {
+                let window_ms = window.as_millis().max(1) as i64;
+                let key = format!("{}:fw:{}:{}:{}", self.prefix, client, route, now / window_ms);
+                RedisScriptCall::new(SCRIPT_RATE_FIXED_WINDOW)
+                    .key(&key)
+                    .arg(*limit)
+                    .arg(window_ms)
+            }
+            RateLimitAlgorithm::SlidingLog { limit, window } => {
+                let key = format!("{}:sl:{}:{}", self.prefix, client, route);
+                RedisScriptCall::new(SCRIPT_RATE_SLIDING_LOG)
+                    .key(&key)
+                    .arg(*limit)
+                    .arg(window.as_millis() as i64)
+                    .arg(now)
+                    .arg(format!("{}-{}", now, uuid::Uuid::new_v4()))
+            }
+            RateLimitAlgorithm::TokenBucket { capacity, refill_per_sec } => {
+                let key = format!("{}:tb:{}:{}", self.prefix, client, route);
+                RedisScriptCall::new(SCRIPT_RATE_TOKEN_BUCKET)
+                    .key(&key)
+                    .arg(*capacity)
+                    .arg(*refill_per_sec)
+                    .arg(now)
+            }
+        };
+        let mut c = self.conn.clone();
+        let (allowed, remaining, retry_ms) : (i64, i64, i64) = get_redis_scripts().invoke_value(&mut c, &call).await?;
+        let decision = RateLimitDecision{
+            allowed: allowed == 1,
+            remaining: remaining.max(0) as u64,
+            retry_after: Duration::from_millis(retry_ms.max(0) as u64),
+        };
+        debug!("rate limit {}:{} : {:?}\n", client, route, decision);
+        Ok(decision)
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimitRejection {
+    pub decision : RateLimitDecision,
+}
+
+impl warp::reject::Reject for RateLimitRejection {}
+
+// rejects with RateLimitRejection once the remote address used up its quota for route.
+// redis failures let the request through, throttling must not take the service down
+pub fn rate_limit_filter(limiter : Arc<RedisRateLimiter>, route : &'static str)
+    -> impl Filter<Extract = (RateLimitDecision,), Error = Rejection> + Clone
+{
+    warp::addr::remote().and_then(move |addr : Option<SocketAddr>| {
+        let limiter = limiter.clone();
+        async move {
+            let client = match addr {
+                Some(v) => { v.ip().to_string() }
+                None => { "unknown".to_string() }
+            };
+            match limiter.check(&client, route).await {
+                Ok(d) => {
+                    if d.allowed {
+                        Ok(d)
+                    } else {
+                        Err(warp::reject::custom(RateLimitRejection{ decision: d }))
+                    }
+                }
+                Err(e) => {
+                    error!("rate limit {}:{} check failed, let through, err {}\n", client, route, e);
+                    Ok(RateLimitDecision{
+                        allowed: true,
+                        remaining: 0,
+                        retry_after: Duration::ZERO,
+                    })
+                }
+            }
+        }
+    })
+}
+
+// maps RateLimitRejection to 429 with Retry-After, use in Filter::recover
+pub async fn handle_rate_limit_rejection(err : Rejection) -> Result<warp::reply::Response, Rejection> {
+    match err.find::<RateLimitRejection>() {
+        Some(r) => {
+            let secs = (r.decision.retry_after.as_millis() as u64).div_ceil(1000);
+            let reply = warp::reply::with_status("too many requests", StatusCode::TOO_MANY_REQUESTS);
+            let reply = warp::reply::with_header(reply, "Retry-After", secs.to_string());
+            Ok(reply.into_response())
+        }
+        None => { Err(err) }
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::time::Duration;
+use chrono::Utc;
+use log::{debug, error, info};
+use redis::{AsyncCommands, Direction, RedisResult};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_codec;
+use crate::libs::redis_impl::{RedisKeyMaker, RedisOp};
+use crate::libs::redis_script::{get_redis_scripts, RedisScriptCall};
+
+const SCRIPT_QUEUE_CLAIM : &str = "reliable_queue_claim";
+const SCRIPT_QUEUE_ACK : &str = "reliable_queue_ack";
+const SCRIPT_QUEUE_REAP : &str = "reliable_queue_reap";
+const SCRIPT_QUEUE_RECOVER : &str = "reliable_queue_recover";
+
+// longest single BLMOVE, the consumer refreshes its last seen time between two
+const QUEUE_POP_BLOCK : usize = 10;
+// the reaper forgets a consumer with nothing in flight once it wasn't seen for this long
+const QUEUE_CONSUMER_IDLE : Duration = Duration::from_secs(60);
+
+// BLMOVE already moved the item, this only records its visibility deadline and owner
+// KEYS : inflight, owner, consumers  ARGV : item, deadline ms, consumer, now ms
+const SCRIPT_QUEUE_CLAIM_CODE : &str = r#"
+redis.call("ZADD", KEYS[1], ARGV[2], ARGV[1])
+redis.call("HSET", KEYS[2], ARGV[1], ARGV[3])
+redis.call("ZADD", KEYS[3], ARGV[4], ARGV[3])
+return 1
+"#;
+
+// KEYS : processing, inflight, owner  ARGV : item, consumer
+const SCRIPT_QUEUE_ACK_CODE : &str = r#"
+if redis.call("HGET", KEYS[3], ARGV[1]) ~= ARGV[2] then
+    return 0
+end
+local n = redis.call("LREM", KEYS[1], 1, ARGV[1])
+redis.call("ZREM", KEYS[2], ARGV[1])
+redis.call("HDEL", KEYS[3], ARGV[1])
+return n
+"#;
+
+// items a consumer moved but never claimed (it died between BLMOVE and the claim) get a
+// deadline first, so they are returned like any other expired item
+// KEYS : inflight, owner, main, processing, consumers
+// ARGV : now ms, limit, consumer, adopt deadline ms, idle since ms
+const SCRIPT_QUEUE_REAP_CODE : &str = r#"
+for _, item in ipairs(redis.call("LRANGE", KEYS[4], 0, -1)) do
+    if not redis.call("ZSCORE", KEYS[1], item) then
+        redis.call("ZADD", KEYS[1], ARGV[4], item)
+    end
+end
+local due = redis.call("ZRANGEBYSCORE", KEYS[1], "-inf", ARGV[1], "LIMIT", 0, ARGV[2])
+for _, item in ipairs(due) do
+    redis.call("LREM", KEYS[4], 1, item)
+    if redis.call("HGET", KEYS[2], item) == ARGV[3] then
+        redis.call("HDEL", KEYS[2], item)
+    end
+    redis.call("ZREM", KEYS[1], item)
+    redis.call("RPUSH", KEYS[3], item)
+end
+if redis.call("ZCARD", KEYS[1]) == 0 and redis.call("LLEN", KEYS[4]) == 0 then
+    local seen = redis.call("ZSCORE", KEYS[5], ARGV[3])
+    if not seen or tonumber(seen) < tonumber(ARGV[5]) then
+        redis.call("ZREM", KEYS[5], ARGV[3])
+    end
+end
+return #due
+"#;
+
+// KEYS : processing, inflight, owner, main, consumers  ARGV : consumer
+const SCRIPT_QUEUE_RECOVER_CODE : &str = r#"
+local items = redis.call("LRANGE", KEYS[1], 0, -1)
+for _, item in ipairs(items) do
+    if redis.call("HGET", KEYS[3], item) == ARGV[1] then
+        redis.call("HDEL", KEYS[3], item)
+    end
+    redis.call("RPUSH", KEYS[4], item)
+end
+redis.call("DEL", KEYS[1], KEYS[2])
+redis.call("ZREM", KEYS[5], ARGV[1])
+return #items
+"#;
+
+async fn register_scripts() {
+    let scripts = get_redis_scripts();
+    for (name, code) in [(SCRIPT_QUEUE_CLAIM, SCRIPT_QUEUE_CLAIM_CODE),
+                         (SCRIPT_QUEUE_ACK, SCRIPT_QUEUE_ACK_CODE),
+                         (SCRIPT_QUEUE_REAP, SCRIPT_QUEUE_REAP_CODE),
+                         (SCRIPT_QUEUE_RECOVER, SCRIPT_QUEUE_RECOVER_CODE)] {
+        if !scripts.is_registered(name).await {
+            scripts.register(name, code).await;
+        }
+    }
+}
+
+// the id keeps equal payloads apart in the processing list and the inflight set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RedisQueueEnvelope<T> {
+    id : String,
+    data : T,
+}
+
+// popped message, ack it once processed. raw is the stored item and identifies it
+#[derive(Debug, Clone)]
+pub struct RedisQueueMessage<T> {
+    pub id : String,
+    pub data : T,
+    raw : Vec<u8>,
+}
+
+// queue where pop moves the item into a per consumer processing list (BLMOVE) instead of
+// removing it. items not acked within the visibility timeout are put back by the reaper, which
+// walks the consumers zset (scored by last seen ms). all keys share the {key} hash tag so the
+// moves stay on one cluster slot and BLMOVE runs on the node owning them
+pub struct RedisReliableQueue<T> {
+    key : String,
+    consumer : String,
+    visibility : Duration,
+    conn : RedisConnection,
+    blocking : Mutex<Option<RedisConnection>>,
+    _marker : PhantomData<T>,
+}
+
+impl<T> RedisReliableQueue<T>
+    where T : Debug + Serialize + DeserializeOwned
+{
+    pub async fn new<K>(conn : RedisConnection, key : &K, visibility : Duration) -> Self
+        where K : RedisKeyMaker
+    {
+        register_scripts().await;
+        Self{
+            key: key.key(),
+            consumer: get_app_instance().get_application_uuid().await,
+            visibility,
+            conn,
+            blocking: Mutex::new(None),
+            _marker: PhantomData,
+        }
+    }
+
+    // a stable consumer name lets recover() pick up this consumer's items after a restart
+    pub fn with_consumer(mut self, consumer : &str) -> Self {
+        self.consumer = consumer.to_string();
+        self
+    }
+
+    pub fn main_key(&self) -> String {
+        format!("{{{}}}", self.key)
+    }
+
+    fn processing_prefix(&self) -> String {
+        format!("{{{}}}:processing:", self.key)
+    }
+
+    pub fn processing_key(&self) -> String {
+        self.processing_key_of(&self.consumer)
+    }
+
+    fn processing_key_of(&self, consumer : &str) -> String {
+        format!("{}{}", self.processing_prefix(), consumer)
+    }
+
+    // visibility deadlines of one consumer's items
+    fn inflight_key_of(&self, consumer : &str) -> String {
+        format!("{{{}}}:inflight:{}", self.key, consumer)
+    }
+
+    fn owner_key(&self) -> String {
+        format!("{{{}}}:owner", self.key)
+    }
+
+    fn consumers_key(&self) -> String {
+        format!("{{{}}}:consumers", self.key)
+    }
+
+    pub async fn push(&self, data : &T) -> types::Result<String> {
+        let envelope = RedisQueueEnvelope{
+            id: uuid::Uuid::new_v4().to_string(),
+            data,
+        };
+        let key = self.main_key();
+        debug!("reliable queue push#{} : {:?}\n", key, &envelope);
+        let j = redis_codec::encode(&envelope)?;
+        let mut c = self.conn.clone();
+        let r : RedisResult<()> = c.lpush(&key, j).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("reliable queue push#{}", key), x));
+        }
+        Ok(envelope.id)
+    }
+
+    pub async fn len(&self) -> types::Result<i64> {
+        let key = self.main_key();
+        let mut c = self.conn.clone();
+        match c.llen(&key).await {
+            Ok(v) => { Ok(v) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("reliable queue len#{}", key), x))
+            }
+        }
+    }
+
+    async fn blocking_conn(&self) -> types::Result<RedisConnection> {
+        let mut x = self.blocking.lock().await;
+        if let Some(c) = x.as_ref() {
+            return Ok(c.clone());
+        }
+        // BLMOVE holds the socket, keep it off the shared pool
+        let c = RedisOp::connect().await?;
+        *x = Some(c.clone());
+        Ok(c)
+    }
+
+    // marks this consumer as alive so the reaper keeps watching its processing list
+    async fn touch(&self) -> types::Result<()> {
+        let key = self.consumers_key();
+        let mut c = self.conn.clone();
+        let r : RedisResult<()> = c.zadd(&key, self.consumer.as_str(), Utc::now().timestamp_millis()).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("reliable queue touch#{}", key), x));
+        }
+        Ok(())
+    }
+
+    async fn claim(&self, raw : &[u8]) -> types::Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let call = RedisScriptCall::new(SCRIPT_QUEUE_CLAIM)
+            .key(&self.inflight_key_of(&self.consumer))
+            .key(&self.owner_key())
+            .key(&self.consumers_key())
+            .arg(raw)
+            .arg(now + self.visibility.as_millis() as i64)
+            .arg(self.consumer.as_str())
+            .arg(now);
+        let mut c = self.conn.clone();
+        let _ : i64 = get_redis_scripts().invoke_value(&mut c, &call).await?;
+        Ok(())
+    }
+
+    // waits up to time_out secs (0 blocks forever), None when nothing arrived.
+    // blocks in slices of QUEUE_POP_BLOCK secs so the consumer stays visible to the reaper
+    pub async fn pop(&self, time_out : usize) -> types::Result<Option<RedisQueueMessage<T>>> {
+        let main = self.main_key();
+        let processing = self.processing_key();
+        let mut left = time_out;
+        let raw = loop {
+            let block = if time_out == 0 { QUEUE_POP_BLOCK } else { left.min(QUEUE_POP_BLOCK) };
+            self.touch().await?;
+            let mut b = self.blocking_conn().await?;
+            let r : RedisResult<Option<Vec<u8>>> = b.blmove(&main, &processing, Direction::Right, Direction::Left, block).await;
+            match r {
+                Ok(Some(v)) => {
+                    break v;
+                }
+                Ok(None) => {}
+                Err(x) => {
+                    *self.blocking.lock().await = None;
+                    return Err(AppCommonError::redis(format!("reliable queue pop#{}", main), x));
+                }
+            }
+            if time_out > 0 {
+                left -= block;
+                if left == 0 {
+                    return Ok(None);
+                }
+            }
+        };
+        self.claim(&raw).await?;
+        let envelope : RedisQueueEnvelope<T> = redis_codec::decode_value(&raw)?;
+        debug!("reliable queue pop#{} : {:?}\n", main, &envelope);
+        Ok(Some(RedisQueueMessage{
+            id: envelope.id,
+            data: envelope.data,
+            raw,
+        }))
+    }
+
+    // false when the message was already put back by the reaper, whoever popped it since keeps it
+    pub async fn ack(&self, msg : &RedisQueueMessage<T>) -> types::Result<bool> {
+        let call = RedisScriptCall::new(SCRIPT_QUEUE_ACK)
+            .key(&self.processing_key())
+            .key(&self.inflight_key_of(&self.consumer))
+            .key(&self.owner_key())
+            .arg(msg.raw.as_slice())
+            .arg(self.consumer.as_str());
+        let mut c = self.conn.clone();
+        let n : i64 = get_redis_scripts().invoke_value(&mut c, &call).await?;
+        Ok(n > 0)
+    }
+
+    // puts messages whose visibility timeout passed back to the head of the main list,
+    // up to limit per consumer. idle consumers left with nothing in flight are forgotten
+    pub async fn reap(&self, limit : usize) -> types::Result<i64> {
+        let key = self.consumers_key();
+        let mut c = self.conn.clone();
+        let r : RedisResult<Vec<String>> = c.zrange(&key, 0, -1).await;
+        let consumers = match r {
+            Ok(v) => { v }
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("reliable queue consumers#{}", key), x));
+            }
+        };
+        let now = Utc::now().timestamp_millis();
+        let adopt = now + self.visibility.as_millis() as i64;
+        let idle = now - QUEUE_CONSUMER_IDLE.as_millis() as i64;
+        let mut total = 0;
+        for consumer in consumers.iter() {
+            let call = RedisScriptCall::new(SCRIPT_QUEUE_REAP)
+                .key(&self.inflight_key_of(consumer))
+                .key(&self.owner_key())
+                .key(&self.main_key())
+                .key(&self.processing_key_of(consumer))
+                .key(&key)
+                .arg(now)
+                .arg(limit)
+                .arg(consumer.as_str())
+                .arg(adopt)
+                .arg(idle);
+            let n : i64 = get_redis_scripts().invoke_value(&mut c, &call).await?;
+            total += n;
+        }
+        Ok(total)
+    }
+
+    // returns everything left in this consumer's processing list, call it on startup
+    pub async fn recover(&self) -> types::Result<i64> {
+        let call = RedisScriptCall::new(SCRIPT_QUEUE_RECOVER)
+            .key(&self.processing_key())
+            .key(&self.inflight_key_of(&self.consumer))
+            .key(&self.owner_key())
+            .key(&self.main_key())
+            .key(&self.consumers_key())
+            .arg(self.consumer.as_str());
+        let mut c = self.conn.clone();
+        let n : i64 = get_redis_scripts().invoke_value(&mut c, &call).await?;
+        if n > 0 {
+            info!("reliable queue#{} recovered {} items of {}\n", self.key, n, self.consumer);
+        }
+        Ok(n)
+    }
+}
+
+impl<T> RedisReliableQueue<T>
+    where T : Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    // reaps every interval until the app exits
+    pub fn start_reaper(self : std::sync::Arc<Self>, interval : Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if get_app_instance().is_exiting().await {
+                    info!("system exit status, stop reliable queue#{} reaper\n", self.key);
+                    break;
+                }
+                match self.reap(100).await {
+                    Ok(n) => {
+                        if n > 0 {
+                            info!("reliable queue#{} returned {} stuck items\n", self.key, n);
+                        }
+                    }
+                    Err(e) => {
+                        error!("reliable queue#{} reap failed, err {}\n", self.key, e);
+                    }
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::time::Duration;
+use log::{debug, info};
+use redis::{AsyncCommands, RedisResult};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::redis_codec;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_impl::{key_slot, RedisKeyMaker, RedisKeyPattern, RedisOp};
+
+// typed persistence for one domain type, values are stored with redis_codec under T::key().
+// every call works on a clone of the multiplexed connection, so &self is enough
+pub struct RedisRepository<T> {
+    conn : RedisConnection,
+    ttl : Option<Duration>,
+    _marker : PhantomData<T>,
+}
+
+impl<T> RedisRepository<T>
+    where T : Debug + Serialize + DeserializeOwned + RedisKeyMaker
+{
+    pub fn new(conn : RedisConnection) -> Self {
+        Self{
+            conn,
+            ttl: None,
+            _marker: PhantomData,
+        }
+    }
+
+    // default expiry for every value written through this repository
+    pub fn with_ttl(mut self, ttl : Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn decode(key : &str, v : Option<Vec<u8>>) -> types::Result<Option<T>> {
+        match v {
+            Some(raw) => {
+                match redis_codec::decode_value(&raw) {
+                    Ok(data) => { Ok(Some(data)) }
+                    Err(e) => {
+                        debug!("repository decode#{} failed\n", key);
+                        Err(e)
+                    }
+                }
+            }
+            None => { Ok(None) }
+        }
+    }
+
+    pub async fn get(&self, key : &str) -> types::Result<Option<T>> {
+        let mut c = self.conn.clone();
+        let r : RedisResult<Option<Vec<u8>>> = c.get(key).await;
+        match r {
+            Ok(v) => {
+                Self::decode(key, v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("repository get#{}", key), x))
+            }
+        }
+    }
+
+    pub async fn exists(&self, key : &str) -> types::Result<bool> {
+        let mut c = self.conn.clone();
+        match c.exists(key).await {
+            Ok(v) => { Ok(v) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("repository exists#{}", key), x))
+            }
+        }
+    }
+
+    pub async fn save(&self, data : &T) -> types::Result<()> {
+        self.save_with_ttl(data, self.ttl).await
+    }
+
+    pub async fn save_with_ttl(&self, data : &T, ttl : Option<Duration>) -> types::Result<()> {
+        let key = data.key();
+        info!("repository save#{} : {:?}\n", key, data);
+        let j = redis_codec::encode(data)?;
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(&key).arg(j);
+        if let Some(t) = ttl {
+            cmd.arg("PX").arg(t.as_millis() as u64);
+        }
+        let mut c = self.conn.clone();
+        let r : RedisResult<()> = cmd.query_async(&mut c).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("repository save#{}", key), x));
+        }
+        Ok(())
+    }
+
+    pub async fn delete(&self, key : &str) -> types::Result<bool> {
+        let mut c = self.conn.clone();
+        let r : RedisResult<i64> = c.del(key).await;
+        match r {
+            Ok(v) => { Ok(v > 0) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("repository delete#{}", key), x))
+            }
+        }
+    }
+
+    // keys of different slots can't share a multi key command in a cluster
+    fn group_by_slot<'a>(keys : impl Iterator<Item = (usize, &'a String)>) -> BTreeMap<u16, Vec<(usize, &'a String)>> {
+        let mut groups : BTreeMap<u16, Vec<(usize, &'a String)>> = BTreeMap::new();
+        for (i, k) in keys {
+            groups.entry(key_slot(k)).or_default().push((i, k));
+        }
+        groups
+    }
+
+    // results keep the order of keys, missing keys give None
+    pub async fn m_get(&self, keys : &[String]) -> types::Result<Vec<Option<T>>> {
+        let mut out : Vec<Option<T>> = keys.iter().map(|_| None).collect();
+        let mut c = self.conn.clone();
+        for (slot, group) in Self::group_by_slot(keys.iter().enumerate()) {
+            debug!("repository m_get : slot={} keys={}\n", slot, group.len());
+            let mut cmd = redis::cmd("MGET");
+            for (_, k) in &group {
+                cmd.arg(k.as_str());
+            }
+            let r : RedisResult<Vec<Option<Vec<u8>>>> = cmd.query_async(&mut c).await;
+            let values = match r {
+                Ok(v) => { v }
+                Err(x) => {
+                    return Err(AppCommonError::redis(format!("repository m_get slot {}", slot), x));
+                }
+            };
+            for ((i, k), v) in group.into_iter().zip(values) {
+                out[i] = Self::decode(k, v)?;
+            }
+        }
+        Ok(out)
+    }
+
+    pub async fn m_set(&self, data : &[T]) -> types::Result<()> {
+        let mut encoded = vec![];
+        for d in data {
+            encoded.push((d.key(), redis_codec::encode(d)?));
+        }
+        let keys : Vec<&String> = encoded.iter().map(|(k, _)| k).collect();
+        let mut c = self.conn.clone();
+        for (slot, group) in Self::group_by_slot(keys.into_iter().enumerate()) {
+            debug!("repository m_set : slot={} keys={}\n", slot, group.len());
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            let mut mset = redis::cmd("MSET");
+            for (i, _) in &group {
+                mset.arg(&encoded[*i].0).arg(&encoded[*i].1);
+            }
+            pipe.add_command(mset).ignore();
+            if let Some(t) = self.ttl {
+                for (_, k) in &group {
+                    pipe.cmd("PEXPIRE").arg(k.as_str()).arg(t.as_millis() as u64).ignore();
+                }
+            }
+            let r : RedisResult<()> = pipe.query_async(&mut c).await;
+            if let Err(x) = r {
+                return Err(AppCommonError::redis(format!("repository m_set slot {}", slot), x));
+            }
+        }
+        Ok(())
+    }
+
+    // hash storage, T is kept as field T::key() of the hash under hash_key
+    pub async fn h_put(&self, hash_key : &str, data : &T) -> types::Result<()> {
+        let field = data.key();
+        let j = redis_codec::encode(data)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        pipe.hset(hash_key, &field, j).ignore();
+        if let Some(t) = self.ttl {
+            pipe.pexpire(hash_key, t.as_millis() as usize).ignore();
+        }
+        let mut c = self.conn.clone();
+        let r : RedisResult<()> = pipe.query_async(&mut c).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("repository h_put#{}.{}", hash_key, field), x));
+        }
+        Ok(())
+    }
+
+    pub async fn h_get(&self, hash_key : &str, field : &str) -> types::Result<Option<T>> {
+        let mut c = self.conn.clone();
+        let r : RedisResult<Option<Vec<u8>>> = c.hget(hash_key, field).await;
+        match r {
+            Ok(v) => {
+                Self::decode(&format!("{}.{}", hash_key, field), v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("repository h_get#{}.{}", hash_key, field), x))
+            }
+        }
+    }
+
+    pub async fn h_get_all(&self, hash_key : &str) -> types::Result<HashMap<String, T>> {
+        let mut c = self.conn.clone();
+        let r : RedisResult<HashMap<String, Vec<u8>>> = c.hgetall(hash_key).await;
+        let all = match r {
+            Ok(v) => { v }
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("repository h_get_all#{}", hash_key), x));
+            }
+        };
+        let mut out = HashMap::new();
+        for (field, raw) in all {
+            if let Some(v) = Self::decode(&format!("{}.{}", hash_key, field), Some(raw))? {
+                out.insert(field, v);
+            }
+        }
+        Ok(out)
+    }
+
+    pub async fn h_remove(&self, hash_key : &str, field : &str) -> types::Result<bool> {
+        let mut c = self.conn.clone();
+        let r : RedisResult<i64> = c.hdel(hash_key, field).await;
+        match r {
+            Ok(v) => { Ok(v > 0) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("repository h_remove#{}.{}", hash_key, field), x))
+            }
+        }
+    }
+}
+
+impl<T> RedisRepository<T>
+    where T : Debug + Serialize + DeserializeOwned + RedisKeyMaker + RedisKeyPattern
+{
+    // every key matching T::pattern() over all cluster primaries
+    pub async fn scan_keys(&self) -> types::Result<Vec<String>> {
+        let mut c = self.conn.clone();
+        RedisOp::scan_match(&mut c, &T::pattern()).await
+    }
+
+    pub async fn scan_values(&self) -> types::Result<Vec<T>> {
+        let keys = self.scan_keys().await?;
+        let values = self.m_get(&keys).await?;
+        Ok(values.into_iter().flatten().collect())
+    }
+}
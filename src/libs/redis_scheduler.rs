@@ -0,0 +1,319 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+use chrono::{DateTime, Local, Utc};
+use log::{debug, error, info, warn};
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::redis_codec;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_impl::{RedisHashFieldMaker, RedisKeyMaker, RedisScoreMemberMaker};
+use crate::libs::redis_pipeline::RedisPipeline;
+use crate::libs::redis_script::{get_redis_scripts, RedisScriptCall};
+
+const SCRIPT_SCHEDULER_CLAIM : &str = "scheduler_claim";
+
+// KEYS : due zset, jobs hash  ARGV : now ms, limit, lease until ms
+// due ids are pushed out to the lease deadline, so a crashed worker's job comes back later
+const SCRIPT_SCHEDULER_CLAIM_CODE : &str = r#"
+local due = redis.call("ZRANGEBYSCORE", KEYS[1], "-inf", ARGV[1], "LIMIT", 0, ARGV[2])
+local out = {}
+for _, id in ipairs(due) do
+    local job = redis.call("HGET", KEYS[2], id)
+    if job then
+        redis.call("ZADD", KEYS[1], ARGV[3], id)
+        table.insert(out, job)
+    else
+        redis.call("ZREM", KEYS[1], id)
+    end
+end
+return out
+"#;
+
+// member of the due zset, scored with the due time in ms
+#[derive(Debug)]
+struct RedisJobSlot {
+    key : String,
+    id : String,
+    due : i64,
+}
+
+impl RedisKeyMaker for RedisJobSlot {
+    fn key(&self) -> String {
+        self.key.clone()
+    }
+}
+
+impl RedisScoreMemberMaker for RedisJobSlot {
+    fn member(&self) -> String {
+        self.id.clone()
+    }
+    fn set_member(&mut self, m : &str) {
+        self.id = m.to_string();
+    }
+    fn score(&self) -> i64 {
+        self.due
+    }
+}
+
+// job record, field id of the jobs hash or an entry of the dead letter list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RedisJobRecord<T> {
+    #[serde(skip)]
+    key : String,
+    id : String,
+    attempts : u32,
+    due : i64,
+    #[serde(default)]
+    last_error : String,
+    data : T,
+}
+
+impl<T> RedisKeyMaker for RedisJobRecord<T> {
+    fn key(&self) -> String {
+        self.key.clone()
+    }
+}
+
+impl<T> RedisHashFieldMaker for RedisJobRecord<T> {
+    fn field(&self) -> String {
+        self.id.clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisJob<T> {
+    pub id : String,
+    // failed runs so far
+    pub attempts : u32,
+    pub due : DateTime<Local>,
+    pub data : T,
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisSchedulerConfig {
+    pub batch : usize,
+    pub poll_interval : Duration,
+    // a claimed job is offered again when not completed within the lease
+    pub lease : Duration,
+    // jobs failing this often go to the dead letter list
+    pub max_attempts : u32,
+    pub backoff_base : Duration,
+    pub backoff_max : Duration,
+}
+
+impl Default for RedisSchedulerConfig {
+    fn default() -> Self {
+        Self{
+            batch: 16,
+            poll_interval: Duration::from_secs(1),
+            lease: Duration::from_secs(60),
+            max_attempts: 5,
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(600),
+        }
+    }
+}
+
+// delay queue on a sorted set: score is the due time, the payload lives in a hash next to it.
+// keys share the {key} hash tag so claims and moves stay atomic on one cluster slot
+pub struct RedisScheduler<T> {
+    key : String,
+    conn : RedisConnection,
+    config : RedisSchedulerConfig,
+    _marker : std::marker::PhantomData<T>,
+}
+
+impl<T> RedisScheduler<T>
+    where T : Debug + Serialize + DeserializeOwned
+{
+    pub async fn new<K>(conn : RedisConnection, key : &K, config : RedisSchedulerConfig) -> Self
+        where K : RedisKeyMaker
+    {
+        let scripts = get_redis_scripts();
+        if !scripts.is_registered(SCRIPT_SCHEDULER_CLAIM).await {
+            scripts.register(SCRIPT_SCHEDULER_CLAIM, SCRIPT_SCHEDULER_CLAIM_CODE).await;
+        }
+        Self{
+            key: key.key(),
+            conn,
+            config,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    fn due_key(&self) -> String {
+        format!("{{{}}}:due", self.key)
+    }
+
+    fn jobs_key(&self) -> String {
+        format!("{{{}}}:jobs", self.key)
+    }
+
+    pub fn dead_key(&self) -> String {
+        format!("{{{}}}:dead", self.key)
+    }
+
+    fn slot(&self, id : &str, due : i64) -> RedisJobSlot {
+        RedisJobSlot{
+            key: self.due_key(),
+            id: id.to_string(),
+            due,
+        }
+    }
+
+    pub async fn schedule_at(&self, data : T, due : DateTime<Local>) -> types::Result<String> {
+        let record = RedisJobRecord{
+            key: self.jobs_key(),
+            id: uuid::Uuid::new_v4().to_string(),
+            attempts: 0,
+            due: due.timestamp_millis(),
+            last_error: String::new(),
+            data,
+        };
+        debug!("scheduler#{} schedule : {:?}\n", self.key, &record);
+        let mut c = self.conn.clone();
+        RedisPipeline::new()
+            .h_set(&record)?
+            .z_add(&self.slot(&record.id, record.due))
+            .transaction(&mut c)
+            .await?;
+        Ok(record.id)
+    }
+
+    pub async fn schedule_in(&self, data : T, delay : Duration) -> types::Result<String> {
+        let due = Local::now() + chrono::Duration::milliseconds(delay.as_millis() as i64);
+        self.schedule_at(data, due).await
+    }
+
+    // due jobs, leased to this caller until complete() / fail() or the lease runs out
+    pub async fn claim_due(&self) -> types::Result<Vec<RedisJob<T>>> {
+        let now = Utc::now().timestamp_millis();
+        let call = RedisScriptCall::new(SCRIPT_SCHEDULER_CLAIM)
+            .key(&self.due_key())
+            .key(&self.jobs_key())
+            .arg(now)
+            .arg(self.config.batch)
+            .arg(now + self.config.lease.as_millis() as i64);
+        let mut c = self.conn.clone();
+        // records are written by RedisPipeline::h_set, so with redis_codec
+        let raw : Vec<Vec<u8>> = get_redis_scripts().invoke_value(&mut c, &call).await?;
+        let mut jobs = vec![];
+        for v in raw {
+            let r : RedisJobRecord<T> = redis_codec::decode_value(&v)?;
+            let due = match DateTime::from_timestamp_millis(r.due) {
+                Some(v) => { v.with_timezone(&Local) }
+                None => { Local::now() }
+            };
+            jobs.push(RedisJob{
+                id: r.id,
+                attempts: r.attempts,
+                due,
+                data: r.data,
+            });
+        }
+        Ok(jobs)
+    }
+
+    fn record(&self, job : RedisJob<T>, last_error : &str) -> RedisJobRecord<T> {
+        RedisJobRecord{
+            key: self.jobs_key(),
+            id: job.id,
+            attempts: job.attempts,
+            due: job.due.timestamp_millis(),
+            last_error: last_error.to_string(),
+            data: job.data,
+        }
+    }
+
+    pub async fn complete(&self, job : &RedisJob<T>) -> types::Result<()> {
+        let mut c = self.conn.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .zrem(self.due_key(), &job.id).ignore()
+            .hdel(self.jobs_key(), &job.id).ignore();
+        let r : RedisResult<()> = pipe.query_async(&mut c).await;
+        if let Err(x) = r {
+            return Err(AppCommonError::redis(format!("scheduler#{} complete {}", self.key, job.id), x));
+        }
+        Ok(())
+    }
+
+    fn backoff(&self, attempts : u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.config.backoff_base.saturating_mul(factor).min(self.config.backoff_max)
+    }
+
+    // reschedules with exponential backoff, returns false when the job went to the dead letter list
+    pub async fn fail(&self, job : RedisJob<T>, err : &str) -> types::Result<bool> {
+        let id = job.id.clone();
+        let mut record = self.record(job, err);
+        record.attempts += 1;
+        let mut c = self.conn.clone();
+        if record.attempts >= self.config.max_attempts {
+            warn!("scheduler#{} job {} dead after {} attempts, err {}\n", self.key, id, record.attempts, err);
+            let j = redis_codec::encode(&record)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .lpush(self.dead_key(), j).ignore()
+                .zrem(self.due_key(), &id).ignore()
+                .hdel(self.jobs_key(), &id).ignore();
+            let r : RedisResult<()> = pipe.query_async(&mut c).await;
+            if let Err(x) = r {
+                return Err(AppCommonError::redis(format!("scheduler#{} dead letter {}", self.key, id), x));
+            }
+            return Ok(false);
+        }
+        record.due = Utc::now().timestamp_millis() + self.backoff(record.attempts).as_millis() as i64;
+        debug!("scheduler#{} job {} retry {} at {}\n", self.key, id, record.attempts, record.due);
+        RedisPipeline::new()
+            .h_set(&record)?
+            .z_add(&self.slot(&id, record.due))
+            .transaction(&mut c)
+            .await?;
+        Ok(true)
+    }
+
+    // polls until the app exits, jobs the handler fails are retried with backoff
+    pub async fn run<F, Fut>(&self, handler : F) -> types::Result<()>
+        where F : Fn(&RedisJob<T>) -> Fut,
+              Fut : Future<Output = types::Result<()>>
+    {
+        info!("scheduler#{} worker start\n", self.key);
+        loop {
+            if get_app_instance().is_exiting().await {
+                info!("system exit status, stop scheduler#{} worker\n", self.key);
+                break;
+            }
+            let jobs = match self.claim_due().await {
+                Ok(v) => { v }
+                Err(e) => {
+                    error!("scheduler#{} claim failed, err {}\n", self.key, e);
+                    tokio::time::sleep(self.config.poll_interval).await;
+                    continue;
+                }
+            };
+            if jobs.is_empty() {
+                tokio::time::sleep(self.config.poll_interval).await;
+                continue;
+            }
+            for job in jobs {
+                let r = match handler(&job).await {
+                    Ok(_) => { self.complete(&job).await }
+                    Err(e) => {
+                        error!("scheduler#{} job {} failed, err {}\n", self.key, job.id, e);
+                        self.fail(job, &e.to_string()).await.map(|_| ())
+                    }
+                };
+                if let Err(e) = r {
+                    error!("scheduler#{} update job failed, err {}\n", self.key, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use redis::{ErrorKind, FromRedisValue, RedisResult, ToRedisArgs};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::json::json_impl;
+use crate::libs::redis_async_pool::RedisConnection;
+
+struct RedisScriptEntry {
+    code : String,
+    sha : String,
+}
+
+// arguments of one script run, the first key decides the cluster node it runs on
+#[derive(Debug, Clone)]
+pub struct RedisScriptCall {
+    name : String,
+    keys : Vec<String>,
+    args : Vec<Vec<u8>>,
+}
+
+impl RedisScriptCall {
+    pub fn new(name : &str) -> Self {
+        Self{
+            name: name.to_string(),
+            keys: vec![],
+            args: vec![],
+        }
+    }
+
+    pub fn key(mut self, key : &str) -> Self {
+        self.keys.push(key.to_string());
+        self
+    }
+
+    pub fn arg<A>(mut self, arg : A) -> Self
+        where A : ToRedisArgs
+    {
+        self.args.extend(arg.to_redis_args());
+        self
+    }
+
+    fn command(&self, name : &str, body : &str) -> redis::Cmd {
+        let mut cmd = redis::cmd(name);
+        cmd.arg(body).arg(self.keys.len());
+        for k in &self.keys {
+            cmd.arg(k.as_str());
+        }
+        for a in &self.args {
+            cmd.arg(a.as_slice());
+        }
+        cmd
+    }
+}
+
+// scripts registered by name and run with EVALSHA.
+// a node that never saw the script (new primary, failover, SCRIPT FLUSH) answers NOSCRIPT,
+// the call is then repeated with EVAL on that node, which also caches the script there
+pub struct RedisScriptRegistry {
+    scripts : Mutex<HashMap<String, Arc<RedisScriptEntry>>>,
+}
+
+impl RedisScriptRegistry {
+    pub fn new() -> Self {
+        Self{
+            scripts: Default::default(),
+        }
+    }
+
+    pub async fn register(&self, name : &str, code : &str) {
+        let sha = redis::Script::new(code).get_hash().to_string();
+        info!("register redis script {} sha {}\n", name, sha);
+        let mut x = self.scripts.lock().await;
+        x.insert(name.to_string(), Arc::new(RedisScriptEntry{
+            code: code.to_string(),
+            sha,
+        }));
+    }
+
+    pub async fn is_registered(&self, name : &str) -> bool {
+        self.scripts.lock().await.contains_key(name)
+    }
+
+    async fn entry(&self, name : &str) -> types::Result<Arc<RedisScriptEntry>> {
+        match self.scripts.lock().await.get(name) {
+            Some(v) => { Ok(v.clone()) }
+            None => {
+                Err(AppCommonError::redis_msg(format!("redis script {} not registered", name)))
+            }
+        }
+    }
+
+    // SCRIPT LOAD is sent to every node of the cluster
+    pub async fn load_all(&self, redis_op : &mut RedisConnection) -> types::Result<()> {
+        let entries : Vec<(String, Arc<RedisScriptEntry>)> = self.scripts.lock().await
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (name, entry) in entries {
+            let r : RedisResult<String> = redis::cmd("SCRIPT").arg("LOAD").arg(entry.code.as_str()).query_async(redis_op).await;
+            match r {
+                Ok(sha) => {
+                    debug!("redis script {} loaded, sha {}\n", name, sha);
+                }
+                Err(x) => {
+                    return Err(AppCommonError::redis(format!("script load {}", name), x));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn invoke_value<R>(&self, redis_op : &mut RedisConnection, call : &RedisScriptCall) -> types::Result<R>
+        where R : FromRedisValue
+    {
+        let entry = self.entry(&call.name).await?;
+        let r : RedisResult<R> = call.command("EVALSHA", &entry.sha).query_async(redis_op).await;
+        match r {
+            Ok(v) => {
+                return Ok(v);
+            }
+            Err(x) => {
+                if x.kind() != ErrorKind::NoScriptError {
+                    return Err(AppCommonError::redis(format!("evalsha {}", call.name), x));
+                }
+                warn!("redis script {} not cached on node, fallback to eval\n", call.name);
+            }
+        }
+        let r : RedisResult<R> = call.command("EVAL", &entry.code).query_async(redis_op).await;
+        match r {
+            Ok(v) => {
+                Ok(v)
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("eval {}", call.name), x))
+            }
+        }
+    }
+
+    // for scripts returning a json document (cjson.encode), nil gives None
+    pub async fn invoke<T>(&self, redis_op : &mut RedisConnection, call : &RedisScriptCall) -> types::Result<Option<T>>
+        where T : DeserializeOwned
+    {
+        let r : Option<String> = self.invoke_value(redis_op, call).await?;
+        match r {
+            Some(stx) => {
+                Ok(Some(json_impl::unmarshal_value(&stx)?))
+            }
+            None => { Ok(None) }
+        }
+    }
+}
+
+impl Default for RedisScriptRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+lazy_static!(
+  static ref REDIS_SCRIPT_INSTANCE : RedisScriptRegistry = RedisScriptRegistry::new();
+);
+
+pub fn get_redis_scripts() -> &'static RedisScriptRegistry { &REDIS_SCRIPT_INSTANCE }
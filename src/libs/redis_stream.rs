@@ -0,0 +1,427 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::time::Duration;
+use chrono::Local;
+use log::{debug, error, info, warn};
+use redis::{AsyncCommands, ErrorKind, FromRedisValue, RedisResult};
+use redis::streams::{StreamClaimReply, StreamId, StreamPendingCountReply, StreamRangeReply, StreamReadOptions, StreamReadReply};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::redis_codec;
+use crate::libs::redis_async_pool::RedisConnection;
+use crate::libs::redis_impl::{RedisKeyMaker, RedisOp};
+
+// stream entries carry the serde payload in a single field
+pub const STREAM_PAYLOAD_FIELD : &str = "payload";
+
+#[derive(Debug, Clone)]
+pub struct RedisStreamEntry<T> {
+    pub id : String,
+    pub data : T,
+}
+
+// entries of one read or claim, undecodable holds the ids whose payload failed to decode
+#[derive(Debug, Clone)]
+pub struct RedisStreamBatch<T> {
+    pub entries : Vec<RedisStreamEntry<T>>,
+    pub undecodable : Vec<String>,
+}
+
+impl<T> RedisStreamBatch<T>
+    where T : DeserializeOwned
+{
+    fn decode(key : &str, ids : &[StreamId]) -> Self {
+        let mut out = Self{
+            entries: vec![],
+            undecodable: vec![],
+        };
+        for id in ids {
+            match RedisStreamOp::decode(key, id) {
+                Ok(v) => { out.entries.push(v) }
+                Err(x) => {
+                    error!("stream#{} entry {} undecodable, err {}\n", key, id.id, x);
+                    out.undecodable.push(id.id.clone());
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisStreamPending {
+    pub id : String,
+    pub consumer : String,
+    pub idle_ms : usize,
+    pub delivered : usize,
+}
+
+pub struct RedisStreamOp {}
+
+impl RedisStreamOp {
+    fn decode<T>(key : &str, entry : &StreamId) -> types::Result<RedisStreamEntry<T>>
+        where T : DeserializeOwned
+    {
+        let stx = match entry.map.get(STREAM_PAYLOAD_FIELD) {
+            Some(v) => {
+                match Vec::<u8>::from_redis_value(v) {
+                    Ok(s) => { s }
+                    Err(x) => {
+                        return Err(AppCommonError::redis(format!("stream#{} entry {} payload", key, entry.id), x));
+                    }
+                }
+            }
+            None => {
+                return Err(AppCommonError::redis_msg(format!("stream#{} entry {} has no payload", key, entry.id)));
+            }
+        };
+        Ok(RedisStreamEntry{
+            id: entry.id.clone(),
+            data: redis_codec::decode_value(&stx)?,
+        })
+    }
+
+    // XADD key * payload <redis_codec value>, returns the entry id
+    pub async fn x_add<T>(redis_op : &mut RedisConnection, data : &T) -> types::Result<String>
+        where T : Debug + Serialize + RedisKeyMaker
+    {
+        let key = data.key();
+        debug!("x_add#{} : {:?}\n", key, &data);
+        let j = redis_codec::encode(data)?;
+        match redis_op.xadd(key.clone(), "*", &[(STREAM_PAYLOAD_FIELD, j)]).await {
+            Ok(v) => { Ok(v) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("x_add#{} failed", key), x))
+            }
+        }
+    }
+
+    // creates the stream as well, an existing group is not an error
+    pub async fn x_group_create(redis_op : &mut RedisConnection, key : &str, group : &str) -> types::Result<()> {
+        let r : RedisResult<()> = redis_op.xgroup_create_mkstream(key, group, "0").await;
+        match r {
+            Ok(_) => {
+                info!("x_group_create#{} group {} created\n", key, group);
+                Ok(())
+            }
+            Err(x) => {
+                if x.kind() == ErrorKind::ExtensionError && x.code() == Some("BUSYGROUP") {
+                    return Ok(());
+                }
+                Err(AppCommonError::redis(format!("x_group_create#{} group {}", key, group), x))
+            }
+        }
+    }
+
+    // new entries for this consumer (id ">"), blocks up to block on the connection
+    pub async fn x_read_group<T>(redis_op : &mut RedisConnection,
+                                 key : &str,
+                                 group : &str,
+                                 consumer : &str,
+                                 count : usize,
+                                 block : Duration
+    ) -> types::Result<RedisStreamBatch<T>>
+        where T : DeserializeOwned
+    {
+        let opts = StreamReadOptions::default()
+            .group(group, consumer)
+            .count(count)
+            .block(block.as_millis() as usize);
+        let r : RedisResult<Option<StreamReadReply>> = redis_op.xread_options(&[key], &[">"], &opts).await;
+        let reply = match r {
+            Ok(Some(v)) => { v }
+            Ok(None) => {
+                return Ok(RedisStreamBatch::decode(key, &[]));
+            }
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("x_read_group#{} group {}", key, group), x));
+            }
+        };
+        let ids : Vec<StreamId> = reply.keys.into_iter().flat_map(|x| x.ids).collect();
+        Ok(RedisStreamBatch::decode(key, &ids))
+    }
+
+    pub async fn x_ack(redis_op : &mut RedisConnection, key : &str, group : &str, ids : &[String]) -> types::Result<i64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        match redis_op.xack(key, group, ids).await {
+            Ok(v) => { Ok(v) }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("x_ack#{} group {}", key, group), x))
+            }
+        }
+    }
+
+    // pending entries from start on ("-" for the oldest)
+    pub async fn x_pending(redis_op : &mut RedisConnection, key : &str, group : &str, start : &str, count : usize) -> types::Result<Vec<RedisStreamPending>> {
+        let r : RedisResult<StreamPendingCountReply> = redis_op.xpending_count(key, group, start, "+", count).await;
+        match r {
+            Ok(v) => {
+                Ok(v.ids.into_iter().map(|x| RedisStreamPending{
+                    id: x.id,
+                    consumer: x.consumer,
+                    idle_ms: x.last_delivered_ms,
+                    delivered: x.times_delivered,
+                }).collect())
+            }
+            Err(x) => {
+                Err(AppCommonError::redis(format!("x_pending#{} group {}", key, group), x))
+            }
+        }
+    }
+
+    // takes over entries idle for at least min_idle, entries deleted meanwhile are skipped
+    pub async fn x_claim<T>(redis_op : &mut RedisConnection,
+                            key : &str,
+                            group : &str,
+                            consumer : &str,
+                            min_idle : Duration,
+                            ids : &[String]
+    ) -> types::Result<RedisStreamBatch<T>>
+        where T : DeserializeOwned
+    {
+        if ids.is_empty() {
+            return Ok(RedisStreamBatch::decode(key, &[]));
+        }
+        let r : RedisResult<StreamClaimReply> = redis_op.xclaim(key, group, consumer, min_idle.as_millis() as usize, ids).await;
+        let reply = match r {
+            Ok(v) => { v }
+            Err(x) => {
+                return Err(AppCommonError::redis(format!("x_claim#{} group {}", key, group), x));
+            }
+        };
+        Ok(RedisStreamBatch::decode(key, &reply.ids))
+    }
+
+    // copies the entries with every field to dead_letter, adding source_id and reason.
+    // returns the ids copied, entries deleted meanwhile count as copied
+    pub async fn x_dead_letter(redis_op : &mut RedisConnection, key : &str, dead_letter : &str, ids : &[String], reason : &str) -> Vec<String> {
+        let mut out = vec![];
+        for id in ids {
+            let r : RedisResult<StreamRangeReply> = redis_op.xrange(key, id, id).await;
+            let reply = match r {
+                Ok(v) => { v }
+                Err(x) => {
+                    error!("stream#{} dead letter entry {} read failed, err {}\n", key, id, x);
+                    continue;
+                }
+            };
+            let mut fields : Vec<(String, Vec<u8>)> = vec![];
+            for e in reply.ids.iter() {
+                for (k, v) in e.map.iter() {
+                    if let Ok(raw) = Vec::<u8>::from_redis_value(v) {
+                        fields.push((k.clone(), raw));
+                    }
+                }
+            }
+            if fields.is_empty() {
+                out.push(id.clone());
+                continue;
+            }
+            fields.push(("source_id".to_string(), id.as_bytes().to_vec()));
+            fields.push(("reason".to_string(), reason.as_bytes().to_vec()));
+            let r : RedisResult<String> = redis_op.xadd(dead_letter, "*", &fields).await;
+            match r {
+                Ok(_) => {
+                    warn!("stream#{} entry {} moved to {}, {}\n", key, id, dead_letter, reason);
+                    out.push(id.clone());
+                }
+                Err(x) => {
+                    error!("stream#{} dead letter entry {} to {} failed, err {}\n", key, id, dead_letter, x);
+                }
+            }
+        }
+        out
+    }
+}
+
+// smallest id after id, XPENDING ranges are inclusive
+fn next_stream_id(id : &str) -> Option<String> {
+    let (ms, seq) = id.split_once('-')?;
+    let seq : u64 = seq.parse().ok()?;
+    match seq.checked_add(1) {
+        Some(v) => { Some(format!("{}-{}", ms, v)) }
+        None => { Some(format!("{}-0", ms.parse::<u64>().ok()?.checked_add(1)?)) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisStreamWorkerConfig {
+    pub batch : usize,
+    pub block : Duration,
+    // pending entries idle this long are taken over from dead consumers
+    pub claim_idle : Duration,
+    pub claim_interval : Duration,
+    // entries delivered this many times are not claimed again but dropped to dead_letter
+    pub max_deliveries : usize,
+    // stream receiving dropped and undecodable entries, None only logs and acks them
+    pub dead_letter : Option<String>,
+}
+
+impl Default for RedisStreamWorkerConfig {
+    fn default() -> Self {
+        Self{
+            batch: 16,
+            block: Duration::from_secs(2),
+            claim_idle: Duration::from_secs(60),
+            claim_interval: Duration::from_secs(10),
+            max_deliveries: 5,
+            dead_letter: None,
+        }
+    }
+}
+
+// consumer group worker: reads new entries, acks the ones the handler accepted and
+// periodically claims entries left pending by dead consumers.
+// a failed handler leaves the entry pending, it is retried once it goes idle until
+// max_deliveries, then it goes to dead_letter like entries that do not decode.
+// run() returns when the app status moves to EXITING
+pub struct RedisStreamWorker<T> {
+    key : String,
+    group : String,
+    consumer : String,
+    config : RedisStreamWorkerConfig,
+    _marker : PhantomData<T>,
+}
+
+impl<T> RedisStreamWorker<T>
+    where T : Debug + DeserializeOwned
+{
+    // consumer defaults to the application uuid
+    pub async fn new(key : &str, group : &str, config : RedisStreamWorkerConfig) -> Self {
+        Self{
+            key: key.to_string(),
+            group: group.to_string(),
+            consumer: get_app_instance().get_application_uuid().await,
+            config,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_consumer(mut self, consumer : &str) -> Self {
+        self.consumer = consumer.to_string();
+        self
+    }
+
+    // acks the entries, after copying them to the dead letter stream when one is configured
+    async fn drop_entries(&self, redis_op : &mut RedisConnection, ids : &[String], reason : &str) {
+        if ids.is_empty() {
+            return;
+        }
+        let ids = match &self.config.dead_letter {
+            Some(v) => { RedisStreamOp::x_dead_letter(redis_op, &self.key, v, ids, reason).await }
+            None => {
+                error!("stream#{} drop {} entries, {}\n", self.key, ids.len(), reason);
+                ids.to_vec()
+            }
+        };
+        if let Err(x) = RedisStreamOp::x_ack(redis_op, &self.key, &self.group, &ids).await {
+            error!("stream#{} ack dropped entries failed, err {}\n", self.key, x);
+        }
+    }
+
+    async fn handle<F, Fut>(&self, redis_op : &mut RedisConnection, batch : RedisStreamBatch<T>, handler : &F)
+        where F : Fn(RedisStreamEntry<T>) -> Fut,
+              Fut : Future<Output = types::Result<()>>
+    {
+        // a payload that failed to decode never will, retrying is pointless
+        self.drop_entries(redis_op, &batch.undecodable, "undecodable").await;
+        let mut acked = vec![];
+        for e in batch.entries {
+            let id = e.id.clone();
+            match handler(e).await {
+                Ok(_) => { acked.push(id) }
+                Err(x) => {
+                    error!("stream#{} entry {} handle failed, left pending, err {}\n", self.key, id, x);
+                }
+            }
+        }
+        if let Err(x) = RedisStreamOp::x_ack(redis_op, &self.key, &self.group, &acked).await {
+            error!("stream#{} ack failed, err {}\n", self.key, x);
+        }
+    }
+
+    // scans the pending list a batch per call from cursor on, wrapping around at its end,
+    // so stale entries behind a batch of busy ones are reached as well
+    async fn claim_stale(&self, redis_op : &mut RedisConnection, cursor : &mut String) -> types::Result<RedisStreamBatch<T>> {
+        let pending = RedisStreamOp::x_pending(redis_op, &self.key, &self.group, cursor, self.config.batch).await?;
+        *cursor = match pending.last() {
+            Some(v) if pending.len() >= self.config.batch => {
+                next_stream_id(&v.id).unwrap_or_else(|| "-".to_string())
+            }
+            _ => { "-".to_string() }
+        };
+        let idle_ms = self.config.claim_idle.as_millis() as usize;
+        let (exhausted, stale) : (Vec<RedisStreamPending>, Vec<RedisStreamPending>) = pending.into_iter()
+            .filter(|x| x.idle_ms >= idle_ms)
+            .partition(|x| x.delivered >= self.config.max_deliveries);
+        let exhausted : Vec<String> = exhausted.into_iter().map(|x| x.id).collect();
+        self.drop_entries(redis_op, &exhausted, "max deliveries reached").await;
+        let stale : Vec<String> = stale.into_iter().map(|x| x.id).collect();
+        if stale.is_empty() {
+            return Ok(RedisStreamBatch::decode(&self.key, &[]));
+        }
+        info!("stream#{} claiming {} stale entries for {}\n", self.key, stale.len(), self.consumer);
+        RedisStreamOp::x_claim(redis_op, &self.key, &self.group, &self.consumer, self.config.claim_idle, &stale).await
+    }
+
+    pub async fn run<F, Fut>(&self, handler : F) -> types::Result<()>
+        where F : Fn(RedisStreamEntry<T>) -> Fut,
+              Fut : Future<Output = types::Result<()>>
+    {
+        // XREADGROUP BLOCK holds the socket, so the worker never reads through the shared pool
+        let mut redis_op = RedisOp::connect().await?;
+        RedisStreamOp::x_group_create(&mut redis_op, &self.key, &self.group).await?;
+        info!("stream#{} worker start, group {} consumer {}\n", self.key, self.group, self.consumer);
+        let mut last_claim = tokio::time::Instant::now();
+        let mut claim_cursor = "-".to_string();
+        let mut last_reconnect = Local::now();
+        loop {
+            if get_app_instance().is_exiting().await {
+                info!("system exit status, stop stream#{} worker {}\n", self.key, self.consumer);
+                break;
+            }
+            if last_claim.elapsed() >= self.config.claim_interval {
+                last_claim = tokio::time::Instant::now();
+                match self.claim_stale(&mut redis_op, &mut claim_cursor).await {
+                    Ok(v) => {
+                        self.handle(&mut redis_op, v, &handler).await;
+                    }
+                    Err(x) => {
+                        warn!("stream#{} claim stale entries failed, err {}\n", self.key, x);
+                    }
+                }
+            }
+            let entries = RedisStreamOp::x_read_group(&mut redis_op,
+                                                      &self.key,
+                                                      &self.group,
+                                                      &self.consumer,
+                                                      self.config.batch,
+                                                      self.config.block).await;
+            match entries {
+                Ok(v) => {
+                    self.handle(&mut redis_op, v, &handler).await;
+                }
+                Err(x) => {
+                    error!("stream#{} read group failed, err {}\n", self.key, x);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    // pings first, only a dead connection is replaced
+                    if !RedisOp::reconnect(&mut redis_op, &mut last_reconnect, "stream worker").await {
+                        continue;
+                    }
+                    last_reconnect = Local::now();
+                    // a restarted server without persistence lost the group as well
+                    if let Err(x) = RedisStreamOp::x_group_create(&mut redis_op, &self.key, &self.group).await {
+                        warn!("stream#{} recreate group failed, err {}\n", self.key, x);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
 pub mod node_register_impl;
+pub mod node_register_etcd;
 pub mod node_uri_path;
 pub mod node_types;
\ No newline at end of file
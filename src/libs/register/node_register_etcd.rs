@@ -0,0 +1,168 @@
+// register this node in etcd, alternative to the http NodeLookup
+//
+// every node is a ServiceNode under <prefix>/<type>/<uid>, bound to its lease. the prefix
+// watch feeds the same per type round robin view RegisterStub builds from the lookup
+
+use std::collections::HashMap;
+use std::time::Duration;
+use etcd_rs::{Client, LeaseId, LeaseKeepAlive};
+use log::{info, warn, error};
+use tokio::time;
+use crate::libs::app::app_inst::get_app_instance;
+use crate::libs::etcd_kv::{EtcdKv, EtcdKvEvent};
+use crate::libs::etcd_lock::{grant_lease, refresh_lease, revoke_lease};
+use crate::libs::register::node_register_impl::get_register;
+use crate::libs::register::node_types::{RegisterNode, ServiceNode};
+
+// the signal hook exits 1s after SIGTERM, the lease has to be revoked before
+const REGISTER_EXIT_CHECK : Duration = Duration::from_millis(200);
+
+async fn is_exiting() -> bool {
+    get_register().is_exit().await || get_app_instance().is_exiting().await
+}
+
+async fn apply_nodes(nodes : &HashMap<String, ServiceNode>) {
+    let v : Vec<RegisterNode> = nodes.values().map(RegisterNode::from).collect();
+    get_register().replace_nodes(v).await;
+}
+
+async fn register_node(kv : &EtcdKv<ServiceNode>, key : &str, node : &ServiceNode, ttl : Duration) -> Option<LeaseId> {
+    let lease_id = match grant_lease(kv.client(), ttl).await {
+        Ok(v) => { v }
+        Err(e) => {
+            error!("etcd register#{} failed, err {}\n", key, e);
+            return None;
+        }
+    };
+    match kv.put_with_lease(key, node, lease_id).await {
+        Ok(_) => {
+            info!("etcd register#{} succeed, lease {}\n", key, lease_id);
+            Some(lease_id)
+        }
+        Err(e) => {
+            error!("etcd register#{} failed, err {}\n", key, e);
+            revoke_lease(kv.client(), key, lease_id).await;
+            None
+        }
+    }
+}
+
+async fn future_etcd_register_handle(kv : EtcdKv<ServiceNode>, node : ServiceNode, ttl : Duration) {
+    let key = format!("{}/{}", node.node_type, node.uid);
+    let mut lease : Option<LeaseId> = None;
+    let mut refreshed = time::Instant::now();
+    let mut lease_alive : Option<LeaseKeepAlive> = None;
+    let mut refresh = time::interval((ttl / 3).max(Duration::from_millis(100)));
+    let mut exit_check = time::interval(REGISTER_EXIT_CHECK);
+    loop {
+        tokio::select! {
+            _ = exit_check.tick() => {
+                if is_exiting().await {
+                    warn!("etcd register procedure exiting due to the system is exiting status\n");
+                    break;
+                }
+            }
+            _ = refresh.tick() => {
+                match lease {
+                    Some(id) => {
+                        if !refresh_lease(kv.client(), &key, id, ttl, &mut refreshed, &mut lease_alive).await {
+                            // the node vanished from the view of others, register again
+                            warn!("etcd register#{} lease {} expired\n", key, id);
+                            lease = None;
+                            lease_alive = None;
+                        }
+                    }
+                    None => {
+                        lease = register_node(&kv, &key, &node, ttl).await;
+                        refreshed = time::Instant::now();
+                    }
+                }
+            }
+        }
+    }
+    // revoking deletes the node key with the lease
+    if let Some(id) = lease {
+        revoke_lease(kv.client(), &key, id).await;
+        info!("etcd de-register#{} procedure finished\n", key);
+    }
+}
+
+async fn future_etcd_nodes_watch_handle(kv : EtcdKv<ServiceNode>) {
+    info!("start update register nodes from etcd thread");
+    let mut exit_check = time::interval(REGISTER_EXIT_CHECK);
+    loop {
+        if is_exiting().await {
+            break;
+        }
+        let range = match kv.list("").await {
+            Ok(v) => { v }
+            Err(e) => {
+                error!("update nodes from etcd failed, err {}\n", e);
+                time::sleep(time::Duration::from_millis(1000)).await;
+                continue;
+            }
+        };
+        let mut nodes : HashMap<String, ServiceNode> = range.entries.into_iter()
+            .map(|x| (x.key, x.value))
+            .collect();
+        apply_nodes(&nodes).await;
+        info!("update register nodes from etcd, {} nodes at revision {}\n", nodes.len(), range.revision);
+        // resumes by itself after disconnects, only ends with the loop
+        let mut watch = kv.watch("", range.revision + 1);
+        loop {
+            tokio::select! {
+                ev = watch.next() => {
+                    match ev {
+                        Some(EtcdKvEvent::Put { entry, .. }) => {
+                            nodes.insert(entry.key, entry.value);
+                        }
+                        Some(EtcdKvEvent::Delete { key, .. }) => {
+                            nodes.remove(&key);
+                        }
+                        Some(EtcdKvEvent::Resync(range)) => {
+                            nodes = range.entries.into_iter()
+                                .map(|x| (x.key, x.value))
+                                .collect();
+                        }
+                        None => { break; }
+                    }
+                    apply_nodes(&nodes).await;
+                }
+                _ = exit_check.tick() => {
+                    if is_exiting().await {
+                        info!("stop update register nodes from etcd\n");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// same info tuple as node_register_impl::start_register_loop, ttl is the node lease
+pub async fn start_etcd_register_loop(
+    client : Client,
+    prefix : String,
+    ttl : Duration,
+    info : (String, String, String, String)
+) {
+    info!("etcd register info : [{:?}]\n", info);
+    let (schema_to_be_register,
+        host_to_be_register,
+        this_node_type,
+        app_uuid) = info;
+
+    let mut node = ServiceNode::new();
+    node.uid = app_uuid;
+    node.node_type = this_node_type;
+    node.api_root = host_to_be_register;
+    if schema_to_be_register == "http" {
+        node.scheme = schema_to_be_register;
+    }
+
+    let kv = EtcdKv::new(client, &format!("{}/", prefix.trim_end_matches('/')));
+
+    tokio::spawn(future_etcd_nodes_watch_handle(kv.clone()));
+
+    tokio::spawn(future_etcd_register_handle(kv, node, ttl));
+}
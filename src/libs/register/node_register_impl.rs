@@ -13,6 +13,8 @@ use warp::http;
 use crate::libs::register::node_types::{HttpRegisterNodes, HttpServiceQueryRequest, RegisterNode, ServiceNode};
 use crate::libs::register::node_uri_path;
 use async_channel::{Receiver, Sender};
+use crate::libs::types;
+use crate::libs::error::AppCommonError;
 
 #[derive(Debug)]
 struct RegisterNodeRR {
@@ -149,7 +151,7 @@ impl RegisterStub {
         path : String,
         this_node_type : String,
         uuid : String
-    ) -> bool {
+    ) -> types::Result<()> {
         let url = self.wrap_http_url(host_node_lookup, path);
         let mut req = ServiceNode::new();
         req.uid = uuid;
@@ -159,31 +161,28 @@ impl RegisterStub {
             req.scheme = schema_to_be_register.clone();
         }
 
-        let ser = json_impl::marshal(&req);
-        let req_body = match ser {
+        let req_body = match json_impl::marshal(&req) {
             Ok(v) => {
                 v
             }
             Err(e) => {
-                error!("error marshal data while send to lookup and for register self, err : {:?}\n", e);
-                return false;
+                return Err(AppCommonError::register("marshal data while send to lookup and for register self", e));
             }
         };
 
-        let (status, _) = http2::http2_client_impl::http2_client_post(url, req_body, false).await;
+        let (status, _) = http2::http2_client_impl::reqwest_post(url, req_body, false).await?;
 
         if status != http::StatusCode::OK.as_u16() {
-            error!("get error status code while send to lookup and for register self, status {}\n", status);
-            return false;
+            return Err(AppCommonError::register_msg(format!("get error status code while send to lookup and for register self, status {}", status)));
         }
 
-        true
+        Ok(())
     }
 
     pub(crate) async fn update(&self,
                                host_node_lookup: String,
                                app_uuid : String,
-    ) -> bool {
+    ) -> types::Result<()> {
 
         let _x = self.update_locker.lock().await;
 
@@ -193,32 +192,25 @@ impl RegisterStub {
         req.from_uid = app_uuid;
         req.type_filter.exclude.push(node_types::TYPES_NODE_TYPE_LOOKUP_NODE.to_string());
 
-        let ser = json_impl::marshal(&req);
-        let req_body = match ser {
+        let req_body = match json_impl::marshal(&req) {
             Ok(v) => {
                 v
             }
             Err(e) => {
-                error!("error marshal data while send to lookup and for request nodes, err : {:?}\n", e);
-                return false;
+                return Err(AppCommonError::register("marshal data while send to lookup and for request nodes", e));
             }
         };
 
-        let (status, body) = http2::http2_client_impl::http2_client_post(url, req_body, true).await;
+        let (status, body) = http2::http2_client_impl::reqwest_post(url, req_body, true).await?;
 
         if status != http::StatusCode::OK.as_u16() {
-            error!("get error status code while send to lookup and for request nodes, status {}\n", status);
-            return false;
+            return Err(AppCommonError::register_msg(format!("get error status code while send to lookup and for request nodes, status {}", status)));
         }
 
         //deserialize
         let mut http_data = HttpRegisterNodes::new();
-        match json_impl::unmarshal(&body, &mut http_data) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("unmarshal http_body {} err {}\n", body, e);
-                return false;
-            }
+        if let Err(e) = json_impl::unmarshal(&body, &mut http_data) {
+            return Err(AppCommonError::register(format!("unmarshal http_body {}", body), e));
         }
 
         // update nodes
@@ -238,7 +230,7 @@ impl RegisterStub {
 
         if http_data.nodes.is_empty() {
             info!("retrieved registered nodes from node lookup is empty\n");
-            return true;
+            return Ok(());
         }
 
         let mut last = 0;
@@ -268,7 +260,37 @@ impl RegisterStub {
                 last = i;
             }
         }
-        true
+        Ok(())
+    }
+
+    // swaps the whole view at once, used by registries that always know every node (etcd).
+    // types without nodes disappear, surviving types keep their round robin position
+    pub(crate) async fn replace_nodes(&self, nodes : Vec<RegisterNode>) {
+        let mut uuid_hash = HashMap::new();
+        let mut type_hash : HashMap<String, Vec<RegisterNode>> = HashMap::new();
+        for i in nodes {
+            uuid_hash.insert(i.uid.clone(), i.clone());
+            type_hash.entry(i.node_type.clone()).or_default().push(i);
+        }
+        {
+            let mut x = self.uuid_store.lock().await;
+            *x = uuid_hash;
+        }
+        let mut node_hash = self.node_type_store.lock().await;
+        node_hash.retain(|k, _| type_hash.contains_key(k));
+        for (node_type, v) in type_hash {
+            match node_hash.get(&node_type) {
+                Some(r) => {
+                    r.lock().await.update(&v);
+                }
+                None => {
+                    let mut node = RegisterNodeRR::new();
+                    node.update(&v);
+                    debug!("add node_type {} nodes {:?} \n", node_type, node);
+                    node_hash.insert(node_type, Mutex::new(node));
+                }
+            }
+        }
     }
 }
 
@@ -300,8 +322,13 @@ async fn register_ac(
                                 path.clone(),
                                 this_node_type.clone(),
                                 app_uuid.clone()).await;
-        if s {
-            return true;
+        match s {
+            Ok(_) => {
+                return true;
+            }
+            Err(e) => {
+                error!("register to lookup {} failed, err {}\n", i, e);
+            }
         }
     }
     false
@@ -312,8 +339,13 @@ async fn update_ac(
     app_uuid : &String,
 )-> bool {
     for i in host_node_lookup {
-        if get_register().update(i.clone(), app_uuid.clone()).await {
-            return true;
+        match get_register().update(i.clone(), app_uuid.clone()).await {
+            Ok(_) => {
+                return true;
+            }
+            Err(e) => {
+                error!("update nodes from lookup {} failed, err {}\n", i, e);
+            }
         }
     }
     false
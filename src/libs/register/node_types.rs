@@ -44,6 +44,10 @@ pub struct RegisterNode {
 
     #[serde(rename="create-time")]
     create_time : String,
+
+    // "http" for plain http nodes, empty means https
+    #[serde(rename="scheme", default, skip_serializing_if="String::is_empty")]
+    pub scheme : String,
 }
 
 impl RegisterNode {
@@ -57,6 +61,19 @@ impl RegisterNode {
     }
 }
 
+// nodes registered in etcd have no serving lookup and carry no create time
+impl From<&ServiceNode> for RegisterNode {
+    fn from(n : &ServiceNode) -> Self {
+        RegisterNode{
+            uid: n.uid.clone(),
+            node_type: n.node_type.clone(),
+            api_root: n.api_root.clone(),
+            scheme: n.scheme.clone(),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct HttpRegisterNodes {
     #[serde(default)]
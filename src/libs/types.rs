@@ -1,2 +1,3 @@
+use crate::libs::error::AppCommonError;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub type Result<T> = std::result::Result<T, AppCommonError>;